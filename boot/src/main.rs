@@ -1,12 +1,25 @@
 use bootloader_locator::locate_bootloader;
-use std::{path::Path, process::Command};
+use std::{env, path::Path, process::Command};
+
+mod symbols;
 
 pub fn main() {
+    // `--embed-symbols <kernel>` only runs the post-link step, for
+    // architectures that don't use the bootloader
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let [flag, kernel] = args.as_slice() {
+        if flag == "--embed-symbols" {
+            symbols::embed(Path::new(kernel)).unwrap();
+            return;
+        }
+    }
+
     let bootloader_manifest = locate_bootloader("bootloader").unwrap();
 
     let kernel_binary = Path::new("target/x86_64-hakkero/debug/hakkero")
         .canonicalize()
         .unwrap();
+    symbols::embed(&kernel_binary).unwrap();
     // the path to the root of this crate, set by cargo
     let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
     // we know that the kernel lives in the parent directory
@@ -0,0 +1,97 @@
+//! Post-link step embedding the kernel symbol table.
+//!
+//! Function symbols are read from the kernel ELF, demangled and written into
+//! its `.ksymtab` section. The layout must match `src/symbols.rs`.
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use rustc_demangle::demangle;
+use std::{convert::TryFrom, error::Error, fs, path::Path};
+
+const SECTION: &str = ".ksymtab";
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 20;
+
+struct Symbol {
+    address: u64,
+    size: u32,
+    name: String,
+}
+
+/// Builds the table, dropping the symbols that don't fit in `capacity`
+/// bytes.
+fn build_table(symbols: &[Symbol], capacity: usize) -> Vec<u8> {
+    let mut count = 0;
+    let mut strings_size = 0;
+    for symbol in symbols {
+        if HEADER_SIZE + (count + 1) * ENTRY_SIZE + strings_size + symbol.name.len() > capacity {
+            eprintln!(
+                "warning: symbol table is full, dropping {} of {} symbols",
+                symbols.len() - count,
+                symbols.len()
+            );
+            break;
+        }
+        count += 1;
+        strings_size += symbol.name.len();
+    }
+    let symbols = &symbols[..count];
+
+    let mut table = Vec::with_capacity(capacity);
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&u32::try_from(count).unwrap().to_le_bytes());
+    table.extend_from_slice(&u32::try_from(strings_size).unwrap().to_le_bytes());
+
+    let mut name_offset = 0;
+    for symbol in symbols {
+        table.extend_from_slice(&symbol.address.to_le_bytes());
+        table.extend_from_slice(&symbol.size.to_le_bytes());
+        table.extend_from_slice(&u32::try_from(name_offset).unwrap().to_le_bytes());
+        table.extend_from_slice(&u32::try_from(symbol.name.len()).unwrap().to_le_bytes());
+        name_offset += symbol.name.len();
+    }
+    for symbol in symbols {
+        table.extend_from_slice(symbol.name.as_bytes());
+    }
+    table.resize(capacity, 0);
+    table
+}
+
+/// Writes the symbol table of the kernel at `kernel_binary` into it.
+pub fn embed(kernel_binary: &Path) -> Result<(), Box<dyn Error>> {
+    let mut data = fs::read(kernel_binary)?;
+
+    let (offset, table) = {
+        let file = object::File::parse(&*data)?;
+        let section = file
+            .section_by_name(SECTION)
+            .ok_or("kernel has no symbol table section")?;
+        let (offset, size) = section
+            .file_range()
+            .ok_or("symbol table section has no file data")?;
+
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .filter(|symbol| {
+                symbol.kind() == SymbolKind::Text && symbol.is_definition() && symbol.size() > 0
+            })
+            .filter_map(|symbol| {
+                Some(Symbol {
+                    address: symbol.address(),
+                    size: u32::try_from(symbol.size()).ok()?,
+                    name: format!("{:#}", demangle(symbol.name().ok()?)),
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+        symbols.dedup_by_key(|symbol| symbol.address);
+
+        (
+            usize::try_from(offset)?,
+            build_table(&symbols, usize::try_from(size)?),
+        )
+    };
+
+    data[offset..offset + table.len()].copy_from_slice(&table);
+    fs::write(kernel_binary, data)?;
+    Ok(())
+}
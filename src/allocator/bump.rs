@@ -1,5 +1,6 @@
 //! Simple bump allocator.
 use super::{align_up, Locked};
+use crate::arch::woint;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -41,29 +42,33 @@ impl Allocator {
 // TODO: Implement downwards instead of upwards
 unsafe impl GlobalAlloc for Locked<Allocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut bump = self.lock();
+        woint(|| {
+            let mut bump = self.lock();
 
-        let alloc_start = align_up(bump.next, layout.align());
-        let alloc_end = match alloc_start.checked_add(layout.size()) {
-            Some(end) => end,
-            None => return ptr::null_mut(), // Out of memory condition
-        };
+            let alloc_start = align_up(bump.next, layout.align());
+            let alloc_end = match alloc_start.checked_add(layout.size()) {
+                Some(end) => end,
+                None => return ptr::null_mut(), // Out of memory condition
+            };
 
-        if alloc_end > bump.heap_end {
-            ptr::null_mut() // Out of memory condition
-        } else {
-            bump.next = alloc_end;
-            bump.allocations += 1;
-            alloc_start as *mut u8
-        }
+            if alloc_end > bump.heap_end {
+                ptr::null_mut() // Out of memory condition
+            } else {
+                bump.next = alloc_end;
+                bump.allocations += 1;
+                alloc_start as *mut u8
+            }
+        })
     }
 
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        let mut bump = self.lock();
+        woint(|| {
+            let mut bump = self.lock();
 
-        bump.allocations -= 1;
-        if bump.allocations == 0 {
-            bump.next = bump.heap_start;
-        }
+            bump.allocations -= 1;
+            if bump.allocations == 0 {
+                bump.next = bump.heap_start;
+            }
+        });
     }
 }
@@ -1,6 +1,7 @@
 //! Simple fixed size block allocator.
 //! Falls back to a linked list allocator when it can't allocate.
 use super::Locked;
+use crate::arch::woint;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
 
@@ -76,41 +77,45 @@ fn list_index(layout: &Layout) -> Option<usize> {
 
 unsafe impl GlobalAlloc for Locked<Allocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        #[allow(clippy::option_if_let_else)]
-        if let Some(index) = list_index(&layout) {
-            if let Some(node) = allocator.list_heads[index].take() {
-                allocator.list_heads[index] = node.next.take();
-                (node as *mut ListNode).cast::<u8>()
+        woint(|| {
+            let mut allocator = self.lock();
+            #[allow(clippy::option_if_let_else)]
+            if let Some(index) = list_index(&layout) {
+                if let Some(node) = allocator.list_heads[index].take() {
+                    allocator.list_heads[index] = node.next.take();
+                    (node as *mut ListNode).cast::<u8>()
+                } else {
+                    // No block exists in list => allocate new block
+                    let block_size = BLOCK_SIZES[index];
+                    // Only works if all block sizes are a power of 2
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    allocator.fallback_alloc(layout)
+                }
             } else {
-                // No block exists in list => allocate new block
-                let block_size = BLOCK_SIZES[index];
-                // Only works if all block sizes are a power of 2
-                let block_align = block_size;
-                let layout = Layout::from_size_align(block_size, block_align).unwrap();
                 allocator.fallback_alloc(layout)
             }
-        } else {
-            allocator.fallback_alloc(layout)
-        }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        if let Some(index) = list_index(&layout) {
-            let new_node = ListNode {
-                next: allocator.list_heads[index].take(),
-            };
-            // Verify that block has size and alignment required for storing node
-            assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-            assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-            #[allow(clippy::cast_ptr_alignment)]
-            let new_node_ptr = ptr.cast::<ListNode>();
-            new_node_ptr.write(new_node);
-            allocator.list_heads[index] = Some(&mut *new_node_ptr);
-        } else {
-            let ptr = NonNull::new(ptr).unwrap();
-            allocator.fallback_allocator.deallocate(ptr, layout);
-        }
+        woint(|| {
+            let mut allocator = self.lock();
+            if let Some(index) = list_index(&layout) {
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                // Verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                #[allow(clippy::cast_ptr_alignment)]
+                let new_node_ptr = ptr.cast::<ListNode>();
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            } else {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        });
     }
 }
@@ -42,6 +42,9 @@ impl<A> Locked<A> {
         }
     }
 
+    /// Locks the allocator. Interrupt handlers allocate too, the scheduler
+    /// does, so this must only be called with interrupts disabled, see
+    /// `arch::woint`.
     pub fn lock(&self) -> MutexGuard<A> {
         self.inner.lock()
     }
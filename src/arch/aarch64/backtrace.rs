@@ -0,0 +1,25 @@
+//! Architecture support for `crate::backtrace`.
+use core::ops::Range;
+
+/// Returns the frame pointer of the caller.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+    fp
+}
+
+/// Whether `len` bytes at `addr` can be read without faulting.
+///
+/// The MMU is off, so only addresses inside the stack region, which every
+/// core's boot stack is carved out of, and the stack of the running thread
+/// are accepted.
+pub fn is_readable(addr: usize, len: usize) -> bool {
+    let stack = unsafe { super::stack_range() };
+    let within = |range: Range<usize>| {
+        addr >= range.start && addr.checked_add(len).map_or(false, |end| end <= range.end)
+    };
+    within(stack.start as usize..stack.end as usize)
+        || crate::thread::current_stack().map_or(false, within)
+}
@@ -1 +1,46 @@
 pub const UART_ADDR: usize = 0x3F20_1000;
+pub const CORE_COUNT: usize = 4;
+/// Bit of the EL1 physical timer in the interrupt source registers.
+pub const TIMER_IRQ: u32 = 1;
+
+/// Addresses of the spin table mailboxes the firmware parks secondary cores on.
+const SPIN_TABLE: [usize; CORE_COUNT] = [0xD8, 0xE0, 0xE8, 0xF0];
+/// Per core timer interrupt control registers of the local interrupt
+/// controller.
+const TIMER_CONTROL: usize = 0x4000_0040;
+/// Per core IRQ source registers of the local interrupt controller.
+const IRQ_SOURCE: usize = 0x4000_0060;
+
+/// Releases `core` from its spin table, making it start executing at `entry`.
+///
+/// # Safety
+/// `entry` must be a valid entry point for a secondary core.
+pub unsafe fn release_core(core: u8, entry: usize) -> bool {
+    core::ptr::write_volatile(SPIN_TABLE[core as usize] as *mut u64, entry as u64);
+    // Make sure the write is visible before waking the parked cores
+    asm!("dsb sy", options(nostack));
+    crate::arch::asm::sev();
+    true
+}
+
+/// Routes the physical timer interrupt of `core` to it as an IRQ.
+///
+/// # Safety
+/// Must be called on every core that uses the timer.
+pub unsafe fn enable_timer_irq(core: usize) {
+    core::ptr::write_volatile((TIMER_CONTROL + 4 * core) as *mut u32, 1 << TIMER_IRQ);
+}
+
+/// Returns the next pending interrupt of `core`. The local interrupts are
+/// level triggered, they stay pending until their source is handled.
+pub fn pending_irq(core: usize) -> Option<u32> {
+    let source = unsafe { core::ptr::read_volatile((IRQ_SOURCE + 4 * core) as *const u32) };
+    if source == 0 {
+        None
+    } else {
+        Some(source.trailing_zeros())
+    }
+}
+
+/// Signals the end of handling `irq`, nothing to do for local interrupts.
+pub fn end_irq(_core: usize, _irq: u32) {}
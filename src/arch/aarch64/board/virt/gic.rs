@@ -0,0 +1,53 @@
+//! GICv2 interrupt controller.
+//!
+//! Only the private peripheral interrupts of the cores are used, which are
+//! enabled in the banked registers of each core.
+
+const DISTRIBUTOR: usize = 0x0800_0000;
+const CPU_INTERFACE: usize = 0x0801_0000;
+
+const GICD_CTLR: usize = 0x000;
+const GICD_ISENABLER: usize = 0x100;
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+/// Interrupt ID the CPU interface returns when nothing is pending.
+const SPURIOUS: u32 = 1023;
+
+unsafe fn write(base: usize, offset: usize, value: u32) {
+    core::ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+unsafe fn read(base: usize, offset: usize) -> u32 {
+    core::ptr::read_volatile((base + offset) as *const u32)
+}
+
+/// Enables the distributor and the CPU interface of the current core, and
+/// the private interrupt `irq` of it.
+///
+/// # Safety
+/// Must be called on the core the interrupt is for.
+pub unsafe fn init(irq: u32) {
+    write(DISTRIBUTOR, GICD_CTLR, 1);
+    write(DISTRIBUTOR, GICD_ISENABLER, 1 << irq);
+    // Let all priorities through
+    write(CPU_INTERFACE, GICC_PMR, 0xFF);
+    write(CPU_INTERFACE, GICC_CTLR, 1);
+}
+
+/// Acknowledges the highest priority pending interrupt of the current core.
+pub fn acknowledge() -> Option<u32> {
+    let irq = unsafe { read(CPU_INTERFACE, GICC_IAR) };
+    if irq & 0x3FF == SPURIOUS {
+        None
+    } else {
+        Some(irq)
+    }
+}
+
+/// Signals the end of handling `irq`, as returned by `acknowledge`.
+pub fn end(irq: u32) {
+    unsafe { write(CPU_INTERFACE, GICC_EOIR, irq) };
+}
@@ -1 +1,40 @@
+pub mod gic;
+pub mod psci;
+
 pub const UART_ADDR: usize = 0x0900_0000;
+pub const CORE_COUNT: usize = 4;
+/// Private interrupt of the EL1 physical timer.
+pub const TIMER_IRQ: u32 = 30;
+
+/// Powers on `core`, making it start executing at `entry`.
+///
+/// # Safety
+/// `entry` must be a valid entry point for a secondary core.
+pub unsafe fn release_core(core: u8, entry: usize) -> bool {
+    match psci::cpu_on(u64::from(core), entry as u64, 0) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("PSCI CPU_ON for core {} failed: {:?}", core, e);
+            false
+        }
+    }
+}
+
+/// Routes the physical timer interrupt of the current core to it.
+///
+/// # Safety
+/// Must be called on every core that uses the timer.
+pub unsafe fn enable_timer_irq(_core: usize) {
+    gic::init(TIMER_IRQ);
+}
+
+/// Returns the next pending interrupt of the current core, which has to be
+/// passed to `end_irq` once handled.
+pub fn pending_irq(_core: usize) -> Option<u32> {
+    gic::acknowledge()
+}
+
+/// Signals the end of handling `irq`.
+pub fn end_irq(_core: usize, irq: u32) {
+    gic::end(irq);
+}
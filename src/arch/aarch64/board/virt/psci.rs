@@ -0,0 +1,63 @@
+//! Power State Coordination Interface calls.
+//!
+//! QEMU's `virt` machine uses the SMC conduit when EL2 is available.
+
+const CPU_ON: u64 = 0xC400_0003;
+
+/// Errors returned by PSCI functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl PsciError {
+    fn from_code(code: i64) -> Result<(), Self> {
+        Err(match code {
+            0 => return Ok(()),
+            -1 => PsciError::NotSupported,
+            -2 => PsciError::InvalidParameters,
+            -3 => PsciError::Denied,
+            -4 => PsciError::AlreadyOn,
+            -5 => PsciError::OnPending,
+            -6 => PsciError::InternalFailure,
+            -7 => PsciError::NotPresent,
+            -8 => PsciError::Disabled,
+            -9 => PsciError::InvalidAddress,
+            code => PsciError::Unknown(code),
+        })
+    }
+}
+
+unsafe fn call(function: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let ret;
+    asm!(
+        "smc #0",
+        inout("x0") function => ret,
+        in("x1") arg0,
+        in("x2") arg1,
+        in("x3") arg2,
+        options(nomem, nostack)
+    );
+    ret
+}
+
+/// Powers on the core with the given MPIDR, which starts executing at `entry`
+/// with `context` in `x0`.
+///
+/// # Safety
+/// `entry` must be a valid entry point for a secondary core.
+///
+/// # Errors
+/// Returns the error reported by the firmware.
+pub unsafe fn cpu_on(target_mpidr: u64, entry: u64, context: u64) -> Result<(), PsciError> {
+    PsciError::from_code(call(CPU_ON, target_mpidr, entry, context))
+}
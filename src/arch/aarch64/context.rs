@@ -0,0 +1,109 @@
+//! Thread contexts and switching between them.
+//!
+//! A context switch stores the callee-saved registers (`x19`-`x30` and the
+//! low halves of `v8`-`v15`, `d8`-`d15`) on the current stack, stores the
+//! stack pointer and loads the one of the next thread, which restores its
+//! registers and returns through its `x30`.
+//!
+//! The other FP/SIMD registers are caller-saved, the compiler already spills
+//! them around the call.
+use super::asm;
+
+global_asm!(
+    r#"
+.section .text
+.global context_switch
+// context_switch(from: *mut usize, to: *const usize)
+context_switch:
+    stp x19, x20, [sp, #-160]!
+    stp x21, x22, [sp, #16]
+    stp x23, x24, [sp, #32]
+    stp x25, x26, [sp, #48]
+    stp x27, x28, [sp, #64]
+    stp x29, x30, [sp, #80]
+    stp d8, d9, [sp, #96]
+    stp d10, d11, [sp, #112]
+    stp d12, d13, [sp, #128]
+    stp d14, d15, [sp, #144]
+    mov x9, sp
+    str x9, [x0]
+    ldr x9, [x1]
+    mov sp, x9
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    ldp d8, d9, [sp, #96]
+    ldp d10, d11, [sp, #112]
+    ldp d12, d13, [sp, #128]
+    ldp d14, d15, [sp, #144]
+    ldp x19, x20, [sp], #160
+    ret
+
+.global context_start
+// First return address of a new thread, calls the entry point in x19
+context_start:
+    blr x19
+    brk #0
+"#
+);
+
+extern "C" {
+    fn context_switch(from: *mut usize, to: *const usize);
+    static context_start: u8;
+}
+
+/// Saved state of a thread that isn't running.
+pub struct Context {
+    sp: usize,
+}
+
+impl Context {
+    /// Creates the context of a thread that is already running, which gets
+    /// filled in when it is switched away from.
+    pub fn current() -> Self {
+        Context { sp: 0 }
+    }
+
+    /// Creates a context that starts executing `entry` on the stack ending
+    /// at `stack_top`, with interrupts still masked from the switch.
+    ///
+    /// # Safety
+    /// The stack must be valid for as long as the context is used.
+    pub unsafe fn new(stack_top: usize, entry: extern "C" fn() -> !) -> Self {
+        // x19-x30 and d8-d15 in the order `context_switch` stores them
+        let mut frame = [0; 20];
+        frame[0] = entry as usize;
+        // x29 stays zero, which ends backtraces
+        frame[11] = &context_start as *const u8 as usize;
+        let sp = (stack_top & !0xF) - core::mem::size_of_val(&frame);
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), sp as *mut usize, frame.len());
+        Context { sp }
+    }
+}
+
+/// Saves the current thread into `from` and continues with `to`, returning
+/// when `from` is switched back to.
+///
+/// # Safety
+/// Must be called with interrupts masked. Both contexts must stay valid until
+/// the switch back, `to` must not be running on any core.
+pub unsafe fn switch(from: *mut Context, to: *mut Context) {
+    context_switch(&mut (*from).sp, &(*to).sp);
+}
+
+/// Unmasks IRQs, for new threads once they are set up.
+pub fn enable_interrupts() {
+    unsafe { asm!("msr daifclr, #2", options(nostack)) };
+}
+
+/// Waits until an interrupt arrives, unless `has_work` returns `true`.
+pub fn wait_for_work(has_work: impl FnOnce() -> bool) {
+    super::woint(|| {
+        if !has_work() {
+            // Masked IRQs still end `wfi`, they are taken after unmasking
+            asm::wfi();
+        }
+    });
+}
@@ -0,0 +1,265 @@
+//! EL1 exception vectors.
+//!
+//! Every vector saves the general purpose registers, `ELR_EL1` and
+//! `SPSR_EL1` into an `ExceptionFrame` on the stack and calls
+//! `exception_entry` with it and the number of the vector. The FP/SIMD
+//! registers are saved below the frame, handlers may use them.
+//!
+//! Debug exceptions go to the watchpoint code and IRQs to the timer, which
+//! may switch threads before the IRQ returns. Anything else is fatal.
+use super::{board, percpu::cpu_id, timer};
+use crate::symbols::Symbolized;
+use core::fmt;
+
+/// Exception class of a breakpoint taken from the current EL.
+const EC_BREAKPOINT: u64 = 0x31;
+/// Exception class of a watchpoint taken from the current EL.
+const EC_WATCHPOINT: u64 = 0x35;
+
+global_asm!(
+    r#"
+.section .text
+.balign 2048
+.global exception_vectors
+exception_vectors:
+.set kind, 0
+.rept 16
+    .balign 0x80
+    sub sp, sp, #272
+    stp x0, x1, [sp, #0]
+    mov x1, #kind
+    b exception_common
+    .set kind, kind + 1
+.endr
+
+exception_common:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x9, elr_el1
+    mrs x10, spsr_el1
+    stp x30, x9, [sp, #240]
+    str x10, [sp, #256]
+
+    sub sp, sp, #528
+    stp q0, q1, [sp, #0]
+    stp q2, q3, [sp, #32]
+    stp q4, q5, [sp, #64]
+    stp q6, q7, [sp, #96]
+    stp q8, q9, [sp, #128]
+    stp q10, q11, [sp, #160]
+    stp q12, q13, [sp, #192]
+    stp q14, q15, [sp, #224]
+    stp q16, q17, [sp, #256]
+    stp q18, q19, [sp, #288]
+    stp q20, q21, [sp, #320]
+    stp q22, q23, [sp, #352]
+    stp q24, q25, [sp, #384]
+    stp q26, q27, [sp, #416]
+    stp q28, q29, [sp, #448]
+    stp q30, q31, [sp, #480]
+    mrs x9, fpcr
+    mrs x10, fpsr
+    stp x9, x10, [sp, #512]
+
+    add x0, sp, #528
+    bl exception_entry
+
+    ldp x9, x10, [sp, #512]
+    msr fpcr, x9
+    msr fpsr, x10
+    ldp q0, q1, [sp, #0]
+    ldp q2, q3, [sp, #32]
+    ldp q4, q5, [sp, #64]
+    ldp q6, q7, [sp, #96]
+    ldp q8, q9, [sp, #128]
+    ldp q10, q11, [sp, #160]
+    ldp q12, q13, [sp, #192]
+    ldp q14, q15, [sp, #224]
+    ldp q16, q17, [sp, #256]
+    ldp q18, q19, [sp, #288]
+    ldp q20, q21, [sp, #320]
+    ldp q22, q23, [sp, #352]
+    ldp q24, q25, [sp, #384]
+    ldp q26, q27, [sp, #416]
+    ldp q28, q29, [sp, #448]
+    ldp q30, q31, [sp, #480]
+    add sp, sp, #528
+
+    ldr x10, [sp, #256]
+    ldp x30, x9, [sp, #240]
+    msr elr_el1, x9
+    msr spsr_el1, x10
+    ldp x28, x29, [sp, #224]
+    ldp x26, x27, [sp, #208]
+    ldp x24, x25, [sp, #192]
+    ldp x22, x23, [sp, #176]
+    ldp x20, x21, [sp, #160]
+    ldp x18, x19, [sp, #144]
+    ldp x16, x17, [sp, #128]
+    ldp x14, x15, [sp, #112]
+    ldp x12, x13, [sp, #96]
+    ldp x10, x11, [sp, #80]
+    ldp x8, x9, [sp, #64]
+    ldp x6, x7, [sp, #48]
+    ldp x4, x5, [sp, #32]
+    ldp x2, x3, [sp, #16]
+    ldp x0, x1, [sp, #0]
+    add sp, sp, #272
+    eret
+"#
+);
+
+extern "C" {
+    static exception_vectors: u8;
+}
+
+/// Register state saved on exception entry.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// `x0` to `x30`.
+    pub x: [u64; 31],
+    pub elr: u64,
+    pub spsr: u64,
+    _padding: u64,
+}
+
+impl ExceptionFrame {
+    /// Returns the frame pointer of the interrupted code.
+    pub fn fp(&self) -> u64 {
+        self.x[29]
+    }
+}
+
+/// Type of an exception, the vector within a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+impl Kind {
+    fn from_vector(vector: u64) -> Self {
+        match vector % 4 {
+            0 => Kind::Synchronous,
+            1 => Kind::Irq,
+            2 => Kind::Fiq,
+            _ => Kind::SError,
+        }
+    }
+}
+
+/// Points `VBAR_EL1` of the current core to the exception vectors.
+///
+/// # Safety
+/// Must be called on every core before exceptions are unmasked.
+pub unsafe fn init() {
+    let vectors = &exception_vectors as *const u8 as u64;
+    asm!("msr vbar_el1, {}", "isb", in(reg) vectors, options(nostack));
+}
+
+/// Report of an exception the kernel can't handle.
+struct CrashReport<'a> {
+    frame: &'a ExceptionFrame,
+    vector: u64,
+    esr: u64,
+    far: u64,
+}
+
+impl fmt::Display for CrashReport<'_> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "unhandled {:?} exception (vector {}) at {}",
+            Kind::from_vector(self.vector),
+            self.vector,
+            Symbolized(self.frame.elr as usize)
+        )?;
+        writeln!(
+            f,
+            "ESR {:#x} (class {:#x}), FAR {:#x}, SPSR {:#x}",
+            self.esr,
+            self.esr >> 26,
+            self.far,
+            self.frame.spsr
+        )?;
+        for (i, pair) in self.frame.x.chunks(2).enumerate() {
+            match pair {
+                [a, b] => writeln!(
+                    f,
+                    "x{:<2} {:#018x}  x{:<2} {:#018x}",
+                    2 * i,
+                    a,
+                    2 * i + 1,
+                    b
+                )?,
+                [a] => writeln!(f, "x{:<2} {:#018x}", 2 * i, a)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_entry(frame: &mut ExceptionFrame, vector: u64) {
+    let esr: u64;
+    let far: u64;
+    unsafe {
+        asm!("mrs {}, esr_el1", out(reg) esr, options(nomem, nostack));
+        asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack));
+    }
+
+    let class = (esr >> 26) & 0x3F;
+    match Kind::from_vector(vector) {
+        Kind::Synchronous if class == EC_BREAKPOINT || class == EC_WATCHPOINT => {
+            #[allow(clippy::cast_possible_truncation)]
+            super::watchpoint::handle(far as usize, frame.elr as usize, frame.fp() as usize);
+        }
+        Kind::Irq => {
+            handle_irq();
+            // IRQs are masked in handlers, so this never interrupted another
+            crate::thread::preempt();
+        }
+        _ => panic!(
+            "{}",
+            CrashReport {
+                frame,
+                vector,
+                esr,
+                far,
+            }
+        ),
+    }
+}
+
+/// Handles the pending IRQs of the current core.
+fn handle_irq() {
+    let core = cpu_id();
+    while let Some(irq) = board::pending_irq(core) {
+        let handled = irq == board::TIMER_IRQ;
+        if handled {
+            timer::interrupt();
+        }
+        board::end_irq(core, irq);
+        // Nothing else is enabled, don't spin on a source that stays pending
+        if !handled {
+            break;
+        }
+    }
+}
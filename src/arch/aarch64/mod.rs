@@ -1,13 +1,35 @@
 //! `AArch64` specific code.
 pub mod asm;
+pub mod backtrace;
 pub mod board;
+pub mod context;
 pub mod device;
+pub mod exception;
+pub mod percpu;
 pub mod register;
+pub mod smp;
+pub mod timer;
+pub mod watchpoint;
 
 pub use asm::hang_cpu;
 
 use core::ops::Range;
 
+/// Runs `f` with IRQs masked, restoring the previous mask afterwards.
+pub fn woint<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack));
+        asm!("msr daifset, #2", options(nostack));
+    }
+    let result = f();
+    unsafe { asm!("msr daif, {}", in(reg) daif, options(nostack)) };
+    result
+}
+
 /// Initializes the required peripherals.
 ///
 /// # Safety
@@ -16,8 +38,14 @@ use core::ops::Range;
 #[inline(always)] // Inline because it will be only used once anyways
 pub unsafe fn init() {
     // Have to include this so logging works lol
+    percpu::init(0);
+    register::cpacr_el1::set(register::cpacr_el1::FPEN);
+    exception::init();
     crate::serial_print!("");
     crate::logger::init();
+    smp::init();
+    timer::init();
+    context::enable_interrupts();
     // Also this too
     log::info!("Initialized all peripherals!");
 }
@@ -30,21 +58,22 @@ pub(crate) macro __entry_point($path:path) {
         use $crate::arch::{asm, register};
 
         if register::core_id() == 0 {
-            use $crate::arch::{asm, bss_range, stack_range};
+            use $crate::arch::{asm, bss_range, smp};
 
             unsafe {
                 // zero bss
                 $crate::memory::zero_volatile(bss_range());
 
                 // setup stack
-                asm::set_sp(stack_range().start as usize);
+                asm::set_sp(smp::stack_top(0));
             }
 
             let entry: fn() -> ! = $path;
             entry()
         }
 
-        // If we aren't core 0 then hang
+        // Secondary cores are started at `smp::_start_secondary` by
+        // `smp::init`, they shouldn't end up here
         asm::hang_cpu()
     }
 }
@@ -0,0 +1,20 @@
+//! Logical CPU IDs through `TPIDR_EL1`.
+//!
+//! The logical ID is the same as the core ID.
+use super::register::tpidr_el1;
+
+/// Returns the logical ID of the current core.
+#[allow(clippy::cast_possible_truncation)]
+#[inline]
+pub fn cpu_id() -> usize {
+    tpidr_el1::read() as usize
+}
+
+/// Stores `id` as the logical ID of the current core.
+///
+/// # Safety
+/// Must be called on every core before anything uses per-CPU variables,
+/// `TPIDR_EL1` is undefined at reset.
+pub unsafe fn init(id: usize) {
+    tpidr_el1::write(id as u64);
+}
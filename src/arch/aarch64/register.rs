@@ -88,6 +88,11 @@ pub mod mpidr_el1 {
 
     read!(u64, "mpidr_el1");
 }
+/// EL1 software thread ID register, holds the logical CPU ID.
+pub mod tpidr_el1 {
+    read!(u64, "tpidr_el1");
+    write!(u64, "tpidr_el1");
+}
 pub mod elr_el2 {
     write!(u64, "elr_el2");
 }
@@ -159,3 +164,28 @@ pub mod currentel {
 
     read!(u64, "currentel");
 }
+/// Architectural feature access control register.
+pub mod cpacr_el1 {
+    /// Don't trap FP/SIMD instructions at EL0 and EL1
+    pub const FPEN: u64 = 0b11 << 20;
+
+    set!(u64, "cpacr_el1");
+    write!(u64, "cpacr_el1");
+    read!(u64, "cpacr_el1");
+}
+/// Frequency of the system counter in Hz.
+pub mod cntfrq_el0 {
+    read!(u64, "cntfrq_el0");
+}
+/// EL1 physical timer control register.
+pub mod cntp_ctl_el0 {
+    /// Timer enabled
+    pub const ENABLE: u64 = 1;
+
+    write!(u64, "cntp_ctl_el0");
+}
+/// EL1 physical timer value, the timer fires once this many counter ticks
+/// have passed.
+pub mod cntp_tval_el0 {
+    write!(u64, "cntp_tval_el0");
+}
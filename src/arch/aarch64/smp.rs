@@ -0,0 +1,142 @@
+//! Symmetric multiprocessing support.
+//!
+//! Secondary cores are released through the board specific mechanism (PSCI
+//! on `virt`, spin tables on `raspi3`) and start executing at
+//! `_start_secondary`. Every core gets its own stack carved from the linker
+//! defined stack region.
+use super::{asm, board, context, exception, percpu, register, stack_range, timer};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+
+/// Per-CPU data area.
+#[derive(Debug)]
+pub struct Cpu {
+    /// Logical ID of the CPU, which is the same as its core ID.
+    pub id: usize,
+    online: AtomicBool,
+}
+
+impl Cpu {
+    const OFFLINE: Cpu = Cpu {
+        id: 0,
+        online: AtomicBool::new(false),
+    };
+
+    /// Returns whether this CPU is started and running kernel code.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Returns whether this CPU is the boot core.
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+}
+
+static CPUS: [Cpu; board::CORE_COUNT] = {
+    let mut cpus = [Cpu::OFFLINE; board::CORE_COUNT];
+    let mut i = 0;
+    while i < board::CORE_COUNT {
+        cpus[i].id = i;
+        i += 1;
+    }
+    cpus
+};
+static WORK: Once<fn() -> !> = Once::new();
+
+/// Returns an iterator over all online CPUs.
+pub fn cpus() -> impl Iterator<Item = &'static Cpu> {
+    CPUS.iter().filter(|cpu| cpu.is_online())
+}
+
+/// Returns the number of online CPUs.
+pub fn cpu_count() -> usize {
+    cpus().count()
+}
+
+/// Returns the data area of the CPU this is called on.
+pub fn current_cpu() -> &'static Cpu {
+    &CPUS[percpu::cpu_id()]
+}
+
+/// Returns the initial stack pointer of `core`.
+///
+/// # Safety
+/// See `stack_range`.
+pub unsafe fn stack_top(core: u8) -> usize {
+    let stack = stack_range();
+    let size = (stack.end as usize - stack.start as usize) / board::CORE_COUNT;
+    // Keep the stack pointer 16 byte aligned
+    (stack.end as usize - core as usize * size) & !0xF
+}
+
+/// Releases all secondary cores and waits for them to come online. They are
+/// parked in `idle` afterwards.
+///
+/// # Safety
+/// Must only be called once, on the boot core.
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe fn init() {
+    // Arbitrary, there is no timer to wait on yet
+    const ONLINE_WAIT_SPINS: usize = 10_000_000;
+
+    CPUS[0].online.store(true, Ordering::Release);
+
+    for cpu in &CPUS[1..] {
+        if !board::release_core(cpu.id as u8, _start_secondary as usize) {
+            continue;
+        }
+        let online = (0..ONLINE_WAIT_SPINS).any(|_| {
+            core::hint::spin_loop();
+            cpu.is_online()
+        });
+        if !online {
+            log::warn!("core {} did not start", cpu.id);
+        }
+    }
+
+    log::info!("{} of {} cores online", cpu_count(), board::CORE_COUNT);
+}
+
+/// Entry point of secondary cores.
+#[no_mangle]
+#[naked]
+pub extern "C" fn _start_secondary() -> ! {
+    let core = register::core_id();
+    unsafe {
+        asm::set_sp(stack_top(core));
+    }
+    secondary_main(core)
+}
+
+fn secondary_main(core: u8) -> ! {
+    unsafe {
+        percpu::init(core as usize);
+        register::cpacr_el1::set(register::cpacr_el1::FPEN);
+        exception::init();
+    }
+    let cpu = &CPUS[core as usize];
+    cpu.online.store(true, Ordering::Release);
+    log::info!("core {} online", cpu.id);
+    unsafe { timer::init() };
+    context::enable_interrupts();
+
+    idle()
+}
+
+/// Parks the current core until work is started with `start_work`.
+pub fn idle() -> ! {
+    loop {
+        if let Some(work) = WORK.get() {
+            work();
+        }
+        asm::wfe();
+    }
+}
+
+/// Makes all parked secondary cores run `work`. Only the first call has an
+/// effect.
+pub fn start_work(work: fn() -> !) {
+    WORK.call_once(|| work);
+    asm::sev();
+}
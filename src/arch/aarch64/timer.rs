@@ -0,0 +1,41 @@
+//! Periodic tick from the EL1 physical timer.
+//!
+//! Every core has its own timer, which ends the time slices of its threads.
+//! The boot core's also drives the tick count and the async timers.
+use super::{
+    board,
+    percpu::cpu_id,
+    register::{cntfrq_el0, cntp_ctl_el0, cntp_tval_el0},
+};
+use crate::time::{self, DEFAULT_TICK_RATE};
+
+/// Counter ticks between two timer interrupts.
+fn interval() -> u64 {
+    cntfrq_el0::read() / DEFAULT_TICK_RATE
+}
+
+/// Starts the timer of the current core and routes its interrupt to it.
+///
+/// # Safety
+/// Must be called once on every core, after the exception vectors are
+/// installed.
+pub unsafe fn init() {
+    let core = cpu_id();
+    if core == 0 {
+        time::set_tick_rate(DEFAULT_TICK_RATE);
+    }
+    cntp_tval_el0::write(interval());
+    cntp_ctl_el0::write(cntp_ctl_el0::ENABLE);
+    board::enable_timer_irq(core);
+}
+
+/// Handles the timer interrupt of the current core and arms the next one.
+pub(super) fn interrupt() {
+    // Rearming also clears the interrupt
+    cntp_tval_el0::write(interval());
+    if cpu_id() == 0 {
+        time::tick();
+        crate::task::timer::advance();
+    }
+    crate::thread::tick();
+}
@@ -0,0 +1,303 @@
+//! Hardware watchpoints using the `DBGWVR`/`DBGWCR` watchpoint and
+//! `DBGBVR`/`DBGBCR` breakpoint registers.
+//!
+//! Only the calling core's registers are programmed. Hits are reported by the
+//! debug exception handler through `handle`, which also clears the
+//! watchpoint, since returning would just hit it again.
+use crate::{backtrace::Backtrace, symbols::Symbolized};
+use core::fmt;
+use spin::Mutex;
+
+/// Number of watchpoint slots. The architecture guarantees at least 2
+/// watchpoints and breakpoints, `set` checks how many there really are.
+pub const COUNT: usize = 4;
+
+const MDSCR_KDE: u64 = 1 << 13;
+const MDSCR_MDE: u64 = 1 << 15;
+
+const CONTROL_ENABLE: u64 = 1;
+/// Only match accesses from EL1.
+const CONTROL_EL1: u64 = 0b01 << 1;
+
+/// What a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Executing the instruction at the address. The length must be 4.
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+/// A watched address range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: usize,
+    /// 1, 2, 4 or 8 bytes, `address` must be aligned to it.
+    pub len: usize,
+    pub kind: Kind,
+}
+
+impl Watchpoint {
+    fn contains(&self, address: usize) -> bool {
+        address >= self.address && address - self.address < self.len
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {} byte(s) at {}",
+            self.kind,
+            self.len,
+            Symbolized(self.address)
+        )
+    }
+}
+
+/// Error returned by `set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    NoFreeSlot,
+    UnsupportedLength,
+    Misaligned,
+}
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; COUNT]> = Mutex::new([None; COUNT]);
+
+macro system_register {
+    (read $name:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $name), out(reg) value, options(nomem, nostack)) };
+        value
+    }},
+    (write $name:literal, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe { asm!(concat!("msr ", $name, ", {}"), in(reg) value, options(nomem, nostack)) };
+    }},
+}
+
+/// Programs breakpoint (`"b"`) or watchpoint (`"w"`) register pair `$n`,
+/// disabling it while the address changes.
+macro load_pair($kind:literal, $n:literal, $address:expr, $control:expr) {{
+    let (address, control): (u64, u64) = ($address, $control);
+    unsafe {
+        asm!(
+            concat!("msr dbg", $kind, "cr", $n, "_el1, xzr"),
+            concat!("msr dbg", $kind, "vr", $n, "_el1, {}"),
+            concat!("msr dbg", $kind, "cr", $n, "_el1, {}"),
+            in(reg) address,
+            in(reg) control,
+            options(nomem, nostack),
+        );
+    }
+}}
+
+fn load_breakpoint(slot: usize, address: u64, control: u64) {
+    match slot {
+        0 => load_pair!("b", "0", address, control),
+        1 => load_pair!("b", "1", address, control),
+        2 => load_pair!("b", "2", address, control),
+        _ => load_pair!("b", "3", address, control),
+    }
+}
+
+fn load_watchpoint(slot: usize, address: u64, control: u64) {
+    match slot {
+        0 => load_pair!("w", "0", address, control),
+        1 => load_pair!("w", "1", address, control),
+        2 => load_pair!("w", "2", address, control),
+        _ => load_pair!("w", "3", address, control),
+    }
+}
+
+/// Returns how many breakpoints and watchpoints the core implements.
+#[allow(clippy::cast_possible_truncation)]
+fn implemented() -> (usize, usize) {
+    let dfr0 = system_register!(read "id_aa64dfr0_el1");
+    let breakpoints = ((dfr0 >> 12) & 0xF) as usize + 1;
+    let watchpoints = ((dfr0 >> 20) & 0xF) as usize + 1;
+    (breakpoints, watchpoints)
+}
+
+/// Starts watching `watchpoint`, returning its slot.
+///
+/// # Errors
+/// Returns an error if all slots are in use or the range can't be watched.
+pub fn set(watchpoint: Watchpoint) -> Result<usize, WatchError> {
+    let len_ok = match watchpoint.kind {
+        Kind::Execute => watchpoint.len == 4,
+        Kind::Write | Kind::ReadWrite => matches!(watchpoint.len, 1 | 2 | 4 | 8),
+    };
+    if !len_ok {
+        return Err(WatchError::UnsupportedLength);
+    }
+    if watchpoint.address % watchpoint.len != 0 {
+        return Err(WatchError::Misaligned);
+    }
+
+    let (breakpoints, watchpoints) = implemented();
+    let available = match watchpoint.kind {
+        Kind::Execute => breakpoints,
+        Kind::Write | Kind::ReadWrite => watchpoints,
+    };
+    let slot = {
+        let mut table = WATCHPOINTS.lock();
+        let slot = table
+            .iter()
+            .take(available)
+            .position(Option::is_none)
+            .ok_or(WatchError::NoFreeSlot)?;
+        table[slot] = Some(watchpoint);
+        slot
+    };
+    load();
+    Ok(slot)
+}
+
+/// Stops watching the watchpoint in `slot`. Returns `false` if the slot was
+/// empty.
+pub fn clear(slot: usize) -> bool {
+    let cleared = WATCHPOINTS
+        .lock()
+        .get_mut(slot)
+        .and_then(Option::take)
+        .is_some();
+    if cleared {
+        load();
+    }
+    cleared
+}
+
+/// Returns the watchpoints in all slots.
+pub fn watchpoints() -> [Option<Watchpoint>; COUNT] {
+    *WATCHPOINTS.lock()
+}
+
+/// Returns the breakpoint and watchpoint control values of a slot.
+fn control(watchpoint: Option<&Watchpoint>) -> (u64, u64) {
+    let watchpoint = match watchpoint {
+        Some(watchpoint) => watchpoint,
+        None => return (0, 0),
+    };
+    match watchpoint.kind {
+        // Match all four bytes of an A64 instruction
+        Kind::Execute => (CONTROL_ENABLE | CONTROL_EL1 | (0b1111 << 5), 0),
+        Kind::Write | Kind::ReadWrite => {
+            let access = if watchpoint.kind == Kind::Write {
+                0b10
+            } else {
+                0b11
+            };
+            // Byte select within the aligned double word
+            let bytes = ((1 << watchpoint.len) - 1) << (watchpoint.address % 8);
+            (
+                0,
+                CONTROL_ENABLE | CONTROL_EL1 | (access << 3) | (bytes << 5),
+            )
+        }
+    }
+}
+
+/// Loads the watchpoint table into the debug registers of the current core.
+/// Registers the core doesn't implement are skipped.
+pub fn load() {
+    let table = watchpoints();
+    let (breakpoints, watchpoints) = implemented();
+
+    for (slot, watchpoint) in table.iter().enumerate() {
+        let (breakpoint_control, watchpoint_control) = control(watchpoint.as_ref());
+        let address = watchpoint.map_or(0, |w| w.address as u64);
+        if slot < breakpoints {
+            load_breakpoint(slot, address & !0b11, breakpoint_control);
+        }
+        if slot < watchpoints {
+            load_watchpoint(slot, address & !0b111, watchpoint_control);
+        }
+    }
+
+    let enabled = table.iter().any(Option::is_some);
+    // Unlock the OS lock, then enable debug exceptions at EL1
+    system_register!(write "oslar_el1", 0);
+    let mdscr = system_register!(read "mdscr_el1");
+    system_register!(
+        write "mdscr_el1",
+        if enabled {
+            mdscr | MDSCR_KDE | MDSCR_MDE
+        } else {
+            mdscr & !(MDSCR_KDE | MDSCR_MDE)
+        }
+    );
+    unsafe {
+        if enabled {
+            asm!("msr daifclr, #8", options(nomem, nostack));
+        }
+        asm!("isb", options(nomem, nostack));
+    }
+}
+
+/// Reports a watchpoint or breakpoint hit and clears it. Called by the debug
+/// exception handler with the faulting address (`FAR_EL1`), the exception
+/// link register and the frame pointer of the interrupted code.
+pub(super) fn handle(far: usize, elr: usize, fp: usize) {
+    let mut hits = [false; COUNT];
+    for (slot, watchpoint) in watchpoints().iter().enumerate() {
+        let watchpoint = match watchpoint {
+            Some(watchpoint) => watchpoint,
+            None => continue,
+        };
+        let hit = match watchpoint.kind {
+            Kind::Execute => watchpoint.address == elr,
+            Kind::Write | Kind::ReadWrite => watchpoint.contains(far),
+        };
+        if hit {
+            log::warn!(
+                "watchpoint {} ({}) hit at {}\n{}",
+                slot,
+                watchpoint,
+                Symbolized(elr),
+                Backtrace::from_frame(elr, fp)
+            );
+            hits[slot] = true;
+        }
+    }
+    // Returning to the access would trigger it again
+    for (slot, _) in hits.iter().enumerate().filter(|(_, hit)| **hit) {
+        clear(slot);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_watchpoint_slots() {
+    static WATCHED: [u64; COUNT + 1] = [0; COUNT + 1];
+
+    serial_print!("test_watchpoint_slots... ");
+    let watchpoint = |i: usize| Watchpoint {
+        address: &WATCHED[i] as *const u64 as usize,
+        len: 8,
+        kind: Kind::Write,
+    };
+    // Only as many slots as the core has watchpoints
+    let available = implemented().1.min(COUNT);
+    let mut slots = [0; COUNT];
+    for (i, slot) in slots.iter_mut().enumerate().take(available) {
+        *slot = set(watchpoint(i)).unwrap();
+    }
+    assert_eq!(set(watchpoint(COUNT)), Err(WatchError::NoFreeSlot));
+
+    for &slot in &slots[..available] {
+        assert!(clear(slot));
+        assert!(!clear(slot));
+    }
+    assert!(watchpoints().iter().all(Option::is_none));
+
+    let misaligned = Watchpoint {
+        address: watchpoint(0).address + 1,
+        ..watchpoint(0)
+    };
+    assert_eq!(set(misaligned), Err(WatchError::Misaligned));
+    serial_println!("[ok]");
+}
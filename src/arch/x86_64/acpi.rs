@@ -0,0 +1,206 @@
+//! Minimal ACPI table parsing.
+//!
+//! Only what is needed to discover processors and platform devices is
+//! implemented; AML is not interpreted.
+use super::memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// Root System Description Pointer.
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below only exist if `revision >= 2`
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all System Description Tables.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the table data following the header.
+    pub fn data(&self) -> &[u8] {
+        let len = self.length as usize - mem::size_of::<Self>();
+        unsafe {
+            slice::from_raw_parts(
+                (self as *const Self)
+                    .cast::<u8>()
+                    .add(mem::size_of::<Self>()),
+                len,
+            )
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let bytes = unsafe {
+            slice::from_raw_parts((self as *const Self).cast::<u8>(), self.length as usize)
+        };
+        checksum(bytes)
+    }
+}
+
+/// A processor local APIC found in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An I/O APIC found in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// Information parsed from the Multiple APIC Description Table.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+}
+
+struct Tables {
+    /// Physical addresses of all tables referenced by the RSDT / XSDT.
+    entries: Vec<PhysAddr>,
+}
+
+static TABLES: Once<Tables> = Once::new();
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Reads a `T` from an unaligned byte slice.
+///
+/// # Panics
+/// Panics if `bytes` is shorter than `T`.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset).cast::<T>()) }
+}
+
+/// Parses the RSDT / XSDT pointed to by the RSDP at `rsdp_addr`.
+///
+/// # Safety
+/// Must only be called once, after the memory module has been initialized.
+/// `rsdp_addr` must be the physical address of a RSDP.
+pub unsafe fn init(rsdp_addr: Option<u64>) {
+    let rsdp_addr = if let Some(addr) = rsdp_addr {
+        PhysAddr::new(addr)
+    } else {
+        log::warn!("no RSDP found, ACPI tables unavailable");
+        return;
+    };
+
+    let rsdp = &*phys_to_virt(rsdp_addr).as_ptr::<Rsdp>();
+    if &rsdp.signature != b"RSD PTR " {
+        log::warn!("invalid RSDP signature");
+        return;
+    }
+
+    let (root_addr, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, mem::size_of::<u64>())
+    } else {
+        (u64::from(rsdp.rsdt_address), mem::size_of::<u32>())
+    };
+
+    let root = &*phys_to_virt(PhysAddr::new(root_addr)).as_ptr::<SdtHeader>();
+    if !root.is_valid() {
+        log::warn!("invalid RSDT / XSDT checksum");
+        return;
+    }
+
+    let data = root.data();
+    let entries = (0..data.len() / entry_size)
+        .map(|i| {
+            PhysAddr::new(if entry_size == mem::size_of::<u64>() {
+                read::<u64>(data, i * entry_size)
+            } else {
+                u64::from(read::<u32>(data, i * entry_size))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    log::info!("found {} ACPI tables", entries.len());
+    TABLES.call_once(|| Tables { entries });
+}
+
+/// Finds the table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES.get()?.entries.iter().find_map(|addr| {
+        let header = unsafe { &*phys_to_virt(*addr).as_ptr::<SdtHeader>() };
+        (&header.signature == signature && header.is_valid()).then(|| header)
+    })
+}
+
+/// Parses the MADT, if present.
+pub fn madt() -> Option<Madt> {
+    const LOCAL_APIC: u8 = 0;
+    const IO_APIC: u8 = 1;
+    const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+    let data = find_table(b"APIC")?.data();
+
+    let mut madt = Madt {
+        local_apic_address: u64::from(read::<u32>(data, 0)),
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+    };
+
+    // Skip the local APIC address and flags
+    let mut offset = 8;
+    while offset + 2 <= data.len() {
+        let kind = data[offset];
+        let len = data[offset + 1] as usize;
+        if len < 2 || offset + len > data.len() {
+            log::warn!("malformed MADT entry at offset {}", offset);
+            break;
+        }
+        let entry = &data[offset..offset + len];
+
+        match kind {
+            LOCAL_APIC => madt.processors.push(Processor {
+                acpi_id: entry[2],
+                apic_id: entry[3],
+                // Bit 0 is "enabled", bit 1 is "online capable"
+                enabled: read::<u32>(entry, 4) & 0b11 != 0,
+            }),
+            IO_APIC => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: read(entry, 4),
+                gsi_base: read(entry, 8),
+            }),
+            LOCAL_APIC_ADDRESS_OVERRIDE => madt.local_apic_address = read(entry, 4),
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Some(madt)
+}
@@ -0,0 +1,31 @@
+//! Architecture support for `crate::backtrace`.
+use super::memory;
+use x86_64::VirtAddr;
+
+/// Returns the frame pointer of the caller.
+#[allow(clippy::inline_always)]
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+    fp
+}
+
+/// Whether `len` bytes at `addr` can be read without faulting.
+pub fn is_readable(addr: usize, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match addr.checked_add(len - 1) {
+        Some(end) => end,
+        None => return false,
+    };
+    // Non-canonical addresses fault even if they would be mapped
+    match (
+        VirtAddr::try_new(addr as u64),
+        VirtAddr::try_new(end as u64),
+    ) {
+        (Ok(start), Ok(end)) => memory::is_mapped(start) && memory::is_mapped(end),
+        _ => false,
+    }
+}
@@ -0,0 +1,114 @@
+//! Thread contexts and switching between them.
+//!
+//! A context switch pushes the callee-saved registers on the current stack,
+//! stores the stack pointer and loads the one of the next thread, which pops
+//! its registers and returns to wherever it called `switch` from. The
+//! caller-saved registers are already saved by the compiler around the call.
+use super::fpu::{self, FpuState};
+use x86_64::instructions::interrupts;
+
+global_asm!(
+    r#"
+.section .text
+.global context_switch
+// context_switch(from: *mut usize, to: *const usize)
+context_switch:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, (%rdi)
+    mov (%rsi), %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+
+.global context_start
+// First return address of a new thread, calls the entry point in r12
+context_start:
+    call *%r12
+    ud2
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn context_switch(from: *mut usize, to: *const usize);
+    static context_start: u8;
+}
+
+/// Saved state of a thread that isn't running.
+pub struct Context {
+    sp: usize,
+    fpu: FpuState,
+}
+
+impl Context {
+    /// Creates the context of a thread that is already running, which gets
+    /// filled in when it is switched away from.
+    pub fn current() -> Self {
+        Context {
+            sp: 0,
+            fpu: FpuState::new(),
+        }
+    }
+
+    /// Creates a context that starts executing `entry` on the stack ending
+    /// at `stack_top`, with interrupts still disabled from the switch.
+    ///
+    /// # Safety
+    /// The stack must be valid for as long as the context is used.
+    pub unsafe fn new(stack_top: usize, entry: extern "C" fn() -> !) -> Self {
+        // In the order `context_switch` pops them
+        let frame = [
+            0,              // r15
+            0,              // r14
+            0,              // r13
+            entry as usize, // r12
+            0,              // rbx
+            0,              // rbp, zero ends backtraces
+            &context_start as *const u8 as usize,
+        ];
+        // `context_start` is entered with an aligned stack
+        let sp = (stack_top & !0xF) - core::mem::size_of_val(&frame);
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), sp as *mut usize, frame.len());
+        Context {
+            sp,
+            fpu: FpuState::new(),
+        }
+    }
+}
+
+/// Saves the current thread into `from` and continues with `to`, returning
+/// when `from` is switched back to.
+///
+/// # Safety
+/// Must be called with interrupts disabled. Both contexts must stay valid
+/// until the switch back, `to` must not be running on any CPU.
+pub unsafe fn switch(from: *mut Context, to: *mut Context) {
+    fpu::switch_out(&mut (*from).fpu);
+    fpu::switch_in(&mut (*to).fpu);
+    context_switch(&mut (*from).sp, &(*to).sp);
+}
+
+/// Enables interrupts, for new threads once they are set up.
+pub fn enable_interrupts() {
+    interrupts::enable();
+}
+
+/// Halts until an interrupt arrives, unless `has_work` returns `true`.
+pub fn wait_for_work(has_work: impl FnOnce() -> bool) {
+    interrupts::disable();
+    if has_work() {
+        interrupts::enable();
+    } else {
+        // If an interrupt made work inbetween, `hlt` returns immediately
+        interrupts::enable_and_hlt();
+    }
+}
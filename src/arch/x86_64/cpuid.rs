@@ -0,0 +1,271 @@
+//! CPU identification and feature detection with CPUID.
+//!
+//! The leaves are read once and cached, `has` is cheap enough for hot paths.
+//! Bits the OS controls, like `Osxsave`, can change after that and are read
+//! again on every check.
+use core::{
+    arch::x86_64::{CpuidResult, __cpuid, __cpuid_count},
+    fmt, str,
+};
+use spin::Once;
+
+const LEAF_VENDOR: u32 = 0x0;
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_EXTENDED_FEATURES: u32 = 0x7;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_INFO: u32 = 0x8000_0001;
+const LEAF_BRAND: u32 = 0x8000_0002;
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// Register of a cached leaf a feature bit lives in.
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Leaf1Ecx,
+    Leaf1Edx,
+    /// Leaf 1 `ecx` as it is now, for bits that reflect OS settings.
+    LiveLeaf1Ecx,
+    Leaf7Ebx,
+    Leaf7Ecx,
+    Extended1Ecx,
+    Extended1Edx,
+    Extended7Edx,
+}
+
+macro_rules! features {
+    ($($(#[$doc:meta])* $feature:ident = $register:ident[$bit:literal] $name:literal,)*) => {
+        /// A CPU feature that can be checked with `has`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Feature {
+            $($(#[$doc])* $feature,)*
+        }
+
+        impl Feature {
+            /// All features, in the order they are logged.
+            pub const ALL: &'static [Feature] = &[$(Feature::$feature,)*];
+
+            fn location(self) -> (Register, u32) {
+                match self {
+                    $(Feature::$feature => (Register::$register, $bit),)*
+                }
+            }
+
+            /// Returns the name Linux uses for the feature in `/proc/cpuinfo`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Feature::$feature => $name,)*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    Fpu = Leaf1Edx[0] "fpu",
+    Tsc = Leaf1Edx[4] "tsc",
+    Msr = Leaf1Edx[5] "msr",
+    Pae = Leaf1Edx[6] "pae",
+    Apic = Leaf1Edx[9] "apic",
+    /// Global pages
+    Pge = Leaf1Edx[13] "pge",
+    Fxsr = Leaf1Edx[24] "fxsr",
+    Sse = Leaf1Edx[25] "sse",
+    Sse2 = Leaf1Edx[26] "sse2",
+    Sse3 = Leaf1Ecx[0] "pni",
+    Ssse3 = Leaf1Ecx[9] "ssse3",
+    /// Process-context identifiers
+    Pcid = Leaf1Ecx[17] "pcid",
+    Sse41 = Leaf1Ecx[19] "sse4_1",
+    Sse42 = Leaf1Ecx[20] "sse4_2",
+    X2apic = Leaf1Ecx[21] "x2apic",
+    /// One-shot local APIC timer with an absolute TSC deadline
+    TscDeadline = Leaf1Ecx[24] "tsc_deadline_timer",
+    Xsave = Leaf1Ecx[26] "xsave",
+    /// `XSAVE` has been enabled in `CR4` by the OS
+    Osxsave = LiveLeaf1Ecx[27] "osxsave",
+    Avx = Leaf1Ecx[28] "avx",
+    Rdrand = Leaf1Ecx[30] "rdrand",
+    /// Running under a hypervisor
+    Hypervisor = Leaf1Ecx[31] "hypervisor",
+    FsGsBase = Leaf7Ebx[0] "fsgsbase",
+    Avx2 = Leaf7Ebx[5] "avx2",
+    Smep = Leaf7Ebx[7] "smep",
+    Invpcid = Leaf7Ebx[10] "invpcid",
+    Avx512f = Leaf7Ebx[16] "avx512f",
+    Rdseed = Leaf7Ebx[18] "rdseed",
+    Smap = Leaf7Ebx[20] "smap",
+    Umip = Leaf7Ecx[2] "umip",
+    Lahf = Extended1Ecx[0] "lahf_lm",
+    /// No-execute page protection
+    Nx = Extended1Edx[20] "nx",
+    /// 1 GiB pages
+    Page1Gb = Extended1Edx[26] "pdpe1gb",
+    Rdtscp = Extended1Edx[27] "rdtscp",
+    LongMode = Extended1Edx[29] "lm",
+    /// The TSC runs at a constant rate in all power states
+    InvariantTsc = Extended7Edx[8] "nonstop_tsc",
+}
+
+/// Identification and features of the CPU.
+#[derive(Debug)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    leaf1: CpuidResult,
+    leaf7: CpuidResult,
+    extended1: CpuidResult,
+    extended7: CpuidResult,
+}
+
+fn empty() -> CpuidResult {
+    CpuidResult {
+        eax: 0,
+        ebx: 0,
+        ecx: 0,
+        edx: 0,
+    }
+}
+
+impl CpuInfo {
+    fn read() -> Self {
+        let leaf0 = unsafe { __cpuid(LEAF_VENDOR) };
+        let max_leaf = leaf0.eax;
+        let max_extended = unsafe { __cpuid(LEAF_EXTENDED_MAX) }.eax;
+        let leaf = |leaf: u32| {
+            let max = if leaf >= LEAF_EXTENDED_MAX {
+                max_extended
+            } else {
+                max_leaf
+            };
+            if leaf <= max {
+                unsafe { __cpuid_count(leaf, 0) }
+            } else {
+                empty()
+            }
+        };
+
+        let mut vendor = [0; 12];
+        for (chunk, register) in vendor.chunks_mut(4).zip(&[leaf0.ebx, leaf0.edx, leaf0.ecx]) {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut brand = [0; 48];
+        for (i, chunk) in brand.chunks_mut(16).enumerate() {
+            #[allow(clippy::cast_possible_truncation)]
+            let result = leaf(LEAF_BRAND + i as u32);
+            for (bytes, register) in chunk
+                .chunks_mut(4)
+                .zip(&[result.eax, result.ebx, result.ecx, result.edx])
+            {
+                bytes.copy_from_slice(&register.to_le_bytes());
+            }
+        }
+
+        let leaf1 = leaf(LEAF_FEATURES);
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((leaf1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model + (((leaf1.eax >> 16) & 0xF) << 4)
+        } else {
+            base_model
+        };
+
+        CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            leaf1,
+            leaf7: leaf(LEAF_EXTENDED_FEATURES),
+            extended1: leaf(LEAF_EXTENDED_INFO),
+            extended7: leaf(LEAF_POWER_MANAGEMENT),
+        }
+    }
+
+    /// Returns the vendor string, e.g. `GenuineIntel`.
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Returns the brand string, if the CPU has one.
+    pub fn brand(&self) -> &str {
+        str::from_utf8(&self.brand)
+            .unwrap_or("")
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+    }
+
+    /// Whether the CPU supports `feature`.
+    pub fn has(&self, feature: Feature) -> bool {
+        let (register, bit) = feature.location();
+        let value = match register {
+            Register::Leaf1Ecx => self.leaf1.ecx,
+            Register::Leaf1Edx => self.leaf1.edx,
+            Register::LiveLeaf1Ecx => unsafe { __cpuid(LEAF_FEATURES) }.ecx,
+            Register::Leaf7Ebx => self.leaf7.ebx,
+            Register::Leaf7Ecx => self.leaf7.ecx,
+            Register::Extended1Ecx => self.extended1.ecx,
+            Register::Extended1Edx => self.extended1.edx,
+            Register::Extended7Edx => self.extended7.edx,
+        };
+        value & (1 << bit) != 0
+    }
+
+    /// Returns a value that formats as the names of the supported features.
+    pub fn features(&self) -> Features {
+        Features(self)
+    }
+}
+
+/// Space separated names of the features a CPU supports.
+pub struct Features<'a>(&'a CpuInfo);
+
+impl fmt::Display for Features<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for feature in Feature::ALL.iter().filter(|feature| self.0.has(**feature)) {
+            if !first {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", feature.name())?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+static CPU_INFO: Once<CpuInfo> = Once::new();
+
+/// Returns the identification and features of the CPU.
+///
+/// All CPUs are assumed to be identical, so this describes the CPU that
+/// first called it.
+pub fn info() -> &'static CpuInfo {
+    CPU_INFO.call_once(CpuInfo::read)
+}
+
+/// Whether the CPU supports `feature`.
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
+
+/// Reads and logs the CPU features.
+pub fn init() {
+    let info = info();
+    log::info!(
+        "CPU: {} family {:#x} model {:#x} stepping {} ({})",
+        info.vendor(),
+        info.family,
+        info.model,
+        info.stepping,
+        info.brand()
+    );
+    log::info!("CPU features: {}", info.features());
+}
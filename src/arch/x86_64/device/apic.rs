@@ -0,0 +1,195 @@
+//! Local APIC driver.
+//!
+//! The local APIC timer of every AP ticks the scheduler of its CPU, like the
+//! PIT does on the BSP.
+use super::pit;
+use crate::{
+    arch::x86_64::{
+        interrupts::irq::{self, IrqResult},
+        memory,
+    },
+    time::DEFAULT_TICK_RATE,
+};
+use core::ptr;
+use spin::Once;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+/// Vector used for spurious interrupts. Lowest 4 bits must be set.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector of the IPI used to wake up halted CPUs.
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_LEVEL_TRIGGERED: u32 = 1 << 15;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divides the timer input clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the timer is measured against the PIT, in microseconds.
+const CALIBRATION_MICROS: u64 = 10_000;
+
+/// Delivery modes for inter-processor interrupts.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum Delivery {
+    Fixed = 0b000 << 8,
+    Nmi = 0b100 << 8,
+    Init = 0b101 << 8,
+    Startup = 0b110 << 8,
+}
+
+/// A memory mapped local APIC. Every CPU sees its own local APIC at the same
+/// address, so a single instance is shared.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u32>(), value) }
+    }
+
+    /// Software enables the local APIC of the current CPU.
+    pub fn enable(&self) {
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            base.write(base.read() | APIC_BASE_ENABLE);
+        }
+        // Accept all interrupts
+        self.write(REG_TPR, 0);
+        self.write(REG_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+    }
+
+    /// Returns the APIC ID of the current CPU.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn id(&self) -> u8 {
+        (self.read(REG_ID) >> 24) as u8
+    }
+
+    /// Signals the end of the current interrupt.
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Sends an IPI to the CPU with the given APIC ID.
+    pub fn send_ipi(&self, apic_id: u8, delivery: Delivery, vector: u8) {
+        let mut low = delivery as u32 | u32::from(vector) | ICR_LEVEL_ASSERT;
+        if let Delivery::Init = delivery {
+            low |= ICR_LEVEL_TRIGGERED;
+        }
+        self.write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        self.write(REG_ICR_LOW, low);
+        self.wait_for_delivery();
+    }
+
+    /// Sends a fixed IPI to all CPUs except the current one.
+    pub fn broadcast_ipi(&self, vector: u8) {
+        self.write(REG_ICR_HIGH, 0);
+        self.write(
+            REG_ICR_LOW,
+            ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | Delivery::Fixed as u32 | u32::from(vector),
+        );
+        self.wait_for_delivery();
+    }
+
+    /// Returns how many timer ticks pass in `micros` microseconds.
+    fn measure_timer(&self, micros: u64) -> u32 {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL, u32::MAX);
+        pit::busy_wait(micros);
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT);
+        // A zero count stops the timer
+        self.write(REG_TIMER_INITIAL, 0);
+        elapsed
+    }
+
+    /// Makes the timer of the current CPU raise `vector` every `count` ticks.
+    fn start_periodic_timer(&self, vector: u8, count: u32) {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(vector));
+        self.write(REG_TIMER_INITIAL, count);
+    }
+
+    fn wait_for_delivery(&self) {
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+/// Vector and initial count of the periodic timer.
+static TIMER: Once<(u8, u32)> = Once::new();
+
+/// Maps the local APIC, enables it on the current CPU and sets up the
+/// wakeup IPI handler.
+///
+/// # Safety
+/// Must only be called once, after the memory module has been initialized.
+/// `address` must be the physical address of the local APIC.
+pub unsafe fn init(address: u64) {
+    let base = memory::map_mmio(PhysAddr::new(address), 4096);
+    LOCAL_APIC.call_once(|| LocalApic { base }).enable();
+
+    irq::claim_vector(SPURIOUS_VECTOR);
+    irq::claim_vector(WAKEUP_VECTOR);
+    // Waking up is all this IPI is for
+    irq::register(WAKEUP_VECTOR, |_| IrqResult::Handled);
+}
+
+/// Returns the local APIC, if it has been initialized.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Measures the local APIC timer against the PIT and installs the handler
+/// of the periodic tick. The timer frequency is the same on every CPU, so
+/// this is only done once, on the BSP.
+///
+/// # Panics
+/// Panics if the local APIC isn't initialized or there is no free vector.
+#[allow(clippy::cast_possible_truncation)]
+pub fn init_timer() {
+    let lapic = local_apic().expect("local APIC not initialized");
+    let ticks = u64::from(lapic.measure_timer(CALIBRATION_MICROS));
+    let count =
+        (ticks * 1_000_000 / CALIBRATION_MICROS / DEFAULT_TICK_RATE).clamp(1, u32::MAX.into());
+
+    let vector = irq::request_vector().expect("no free vector for the local APIC timer");
+    irq::register(vector, |_| {
+        crate::thread::tick();
+        IrqResult::Handled
+    });
+    TIMER.call_once(|| (vector, count as u32));
+}
+
+/// Starts the periodic tick on the current CPU. Does nothing if `init_timer`
+/// wasn't called.
+pub fn start_timer() {
+    if let (Some(lapic), Some(&(vector, count))) = (local_apic(), TIMER.get()) {
+        lapic.start_periodic_timer(vector, count);
+    }
+}
@@ -0,0 +1,138 @@
+//! High Precision Event Timer.
+//!
+//! Only the main counter is used, as a clock source and to calibrate the TSC.
+use crate::{
+    arch::x86_64::{acpi, memory},
+    time::clocksource::ClockSource,
+};
+use core::{convert::TryInto, ptr};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIGURATION: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CONF_ENABLE: u64 = 1;
+
+/// Offset of the base address in the data of the ACPI `HPET` table.
+const TABLE_ADDRESS_OFFSET: usize = 8;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// A memory mapped HPET block.
+pub struct Hpet {
+    base: VirtAddr,
+    /// Length of a counter tick in femtoseconds.
+    period: u64,
+    wide: bool,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + reg).as_mut_ptr::<u64>(), value) }
+    }
+
+    /// Returns the value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    /// Returns the frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period
+    }
+
+    /// Whether the main counter is 64 bits wide. 32 bit counters wrap
+    /// around after a few minutes.
+    pub fn is_64bit(&self) -> bool {
+        self.wide
+    }
+
+    /// Spins until the main counter advanced by at least `ticks`.
+    pub fn wait_ticks(&self, ticks: u64) {
+        let start = self.counter();
+        while self.counter().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        // A 32 bit counter wraps every few minutes, and tickless idle can
+        // halt for longer than that, so it would go backwards. Rated below
+        // `Ticks` so it is never picked.
+        if self.wide {
+            250
+        } else {
+            0
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn now_ns(&self) -> u64 {
+        (u128::from(self.counter()) * u128::from(self.period) / FEMTOS_PER_NANO) as u64
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// Looks for an HPET in the ACPI tables, maps and enables it.
+///
+/// # Safety
+/// Must only be called once, after `acpi::init`.
+pub unsafe fn init() {
+    let address = match acpi::find_table(b"HPET").and_then(|table| {
+        let bytes = table
+            .data()
+            .get(TABLE_ADDRESS_OFFSET..TABLE_ADDRESS_OFFSET + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }) {
+        Some(address) => address,
+        None => {
+            log::info!("no HPET found");
+            return;
+        }
+    };
+
+    let base = memory::map_mmio(PhysAddr::new(address), 4096);
+    let mut hpet = Hpet {
+        base,
+        period: 0,
+        wide: false,
+    };
+    let capabilities = hpet.read(REG_CAPABILITIES);
+    hpet.period = capabilities >> 32;
+    hpet.wide = capabilities & CAP_COUNTER_64BIT != 0;
+    if hpet.period == 0 {
+        log::warn!("HPET reports a period of zero, ignoring it");
+        return;
+    }
+
+    let conf = hpet.read(REG_CONFIGURATION);
+    hpet.write(REG_CONFIGURATION, conf | CONF_ENABLE);
+
+    log::info!(
+        "HPET at {:#x}, {} Hz, {} bit counter",
+        address,
+        hpet.frequency(),
+        if hpet.wide { 64 } else { 32 }
+    );
+    HPET.call_once(|| hpet);
+}
+
+/// Returns the HPET, if one has been found.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
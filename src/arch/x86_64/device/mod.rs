@@ -1,10 +1,15 @@
 use bootloader::boot_info::FrameBuffer;
 
+pub mod apic;
+pub mod hpet;
 pub mod pic8259;
+pub mod pit;
+pub mod rtc;
 pub mod uart16550;
 pub mod vga;
 
 pub fn init(framebuffer: Option<&'static mut FrameBuffer>) {
+    pit::init(crate::time::DEFAULT_TICK_RATE);
     pic8259::init();
     uart16550::init();
     if let Some(framebuffer) = framebuffer {
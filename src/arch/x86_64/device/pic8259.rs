@@ -1,31 +1,51 @@
+use crate::arch::x86_64::interrupts::{
+    irq::{self, IrqResult},
+    TrapFrame,
+};
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::InterruptStackFrame;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Initializes the PICs, installs the keyboard handler and enables
+/// interrupts.
 pub fn init() {
     unsafe {
         PICS.lock().initialize();
     };
+    for vector in PIC_1_OFFSET..PIC_2_OFFSET + 8 {
+        irq::claim_vector(vector);
+    }
+    irq::register(InterruptIndex::Keyboard.as_u8(), keyboard_interrupt_handler);
     x86_64::instructions::interrupts::enable();
 }
 
-/// Convenience function to notify the end of an interrupt.
-pub fn send_eoi(int_index: InterruptIndex) {
+/// Returns whether the given vector belongs to the PICs.
+pub fn handles(vector: u8) -> bool {
+    (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&vector)
+}
+
+/// Notifies the PICs of the end of the interrupt with the given vector.
+pub fn notify_end_of_interrupt(vector: u8) {
     unsafe {
-        PICS.lock().notify_end_of_interrupt(int_index.as_u8());
+        PICS.lock().notify_end_of_interrupt(vector);
     }
 }
 
+/// Convenience function to notify the end of an interrupt.
+pub fn send_eoi(int_index: InterruptIndex) {
+    notify_end_of_interrupt(int_index.as_u8());
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Com2 = PIC_1_OFFSET + 3,
 }
 
 impl InterruptIndex {
@@ -38,14 +58,10 @@ impl InterruptIndex {
     }
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    send_eoi(InterruptIndex::Timer);
-}
-
-pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler(_frame: &mut TrapFrame) -> IrqResult {
     let mut port = x86_64::instructions::port::PortReadOnly::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     super::super::task::keyboard::add_scancode(scancode);
 
-    send_eoi(InterruptIndex::Keyboard);
+    IrqResult::Handled
 }
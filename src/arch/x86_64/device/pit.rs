@@ -0,0 +1,140 @@
+//! Intel 8253/8254 Programmable Interval Timer.
+//!
+//! Channel 0 is the kernel's tick source, channel 2 is used for busy waiting.
+//! When idle, the executor can switch channel 0 to one-shot mode to only
+//! be interrupted when a timer expires, and resumes the periodic tick after.
+use super::pic8259::InterruptIndex;
+use crate::{
+    arch::x86_64::interrupts::{
+        irq::{self, IrqResult},
+        TrapFrame,
+    },
+    time::{self, Duration},
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock in Hz.
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+/// Controls the channel 2 gate and exposes its output.
+const SPEAKER_PORT: u16 = 0x61;
+
+static CHANNEL_2_LOCK: Mutex<()> = Mutex::new(());
+/// Divisor of the periodic tick, for `resume_periodic`.
+static PERIODIC_DIVISOR: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 to tick at `hz` Hz and installs the tick handler.
+pub fn init(hz: u64) {
+    set_frequency(hz);
+    irq::register(InterruptIndex::Timer.as_u8(), timer_interrupt_handler);
+}
+
+/// Programs channel 0 to fire periodically at (close to) `hz` Hz.
+///
+/// # Panics
+/// Panics if `hz` is zero.
+#[allow(clippy::cast_possible_truncation)]
+pub fn set_frequency(hz: u64) {
+    assert!(hz > 0, "PIT frequency can't be zero");
+    // A divisor of zero means 0x10000
+    let divisor = (BASE_FREQUENCY / hz).clamp(1, 0x10000);
+
+    // Channel 0, lobyte/hibyte, mode 2 (rate generator)
+    program_channel_0(0b0011_0100, divisor);
+    PERIODIC_DIVISOR.store(divisor, Ordering::Relaxed);
+
+    time::set_tick_rate(BASE_FREQUENCY / divisor);
+}
+
+/// Goes back to the periodic tick after `set_oneshot` or `stop`.
+pub fn resume_periodic() {
+    let divisor = PERIODIC_DIVISOR.load(Ordering::Relaxed);
+    if divisor != 0 {
+        program_channel_0(0b0011_0100, divisor);
+    }
+}
+
+/// Programs channel 0 to fire once after `duration`, stopping the periodic
+/// tick. Durations longer than about 55 ms fire early.
+pub fn set_oneshot(duration: Duration) {
+    // Round up, firing early would only cause another one-shot
+    let ticks = (duration.as_nanos() * u128::from(BASE_FREQUENCY) + 999_999_999) / 1_000_000_000;
+    #[allow(clippy::cast_possible_truncation)]
+    let divisor = ticks.clamp(1, 0xFFFF) as u64;
+    // Channel 0, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    program_channel_0(0b0011_0000, divisor);
+}
+
+/// Stops channel 0 from firing until it is reprogrammed.
+pub fn stop() {
+    // Setting the mode stops the counter until a new count is written
+    crate::arch::woint(|| unsafe { Port::<u8>::new(COMMAND_PORT).write(0b0011_0000) });
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn program_channel_0(mode: u8, divisor: u64) {
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut channel_0 = Port::<u8>::new(CHANNEL_0_PORT);
+
+    crate::arch::woint(|| unsafe {
+        command.write(mode);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    });
+}
+
+fn timer_interrupt_handler(_frame: &mut TrapFrame) -> IrqResult {
+    time::tick();
+    crate::task::timer::advance();
+    crate::thread::tick();
+    IrqResult::Handled
+}
+
+/// Spins for at least `micros` microseconds using channel 2.
+///
+/// This doesn't rely on interrupts, so it can be used before any other time
+/// source is available.
+pub fn busy_wait(micros: u64) {
+    // Channel 2 counts down from at most 0xFFFF, so long waits are split up
+    const MAX_MICROS: u64 = 0xFFFF * 1_000_000 / BASE_FREQUENCY;
+
+    let mut remaining = micros;
+    while remaining > 0 {
+        let step = remaining.min(MAX_MICROS);
+        busy_wait_ticks(step * BASE_FREQUENCY / 1_000_000);
+        remaining -= step;
+    }
+}
+
+/// Spins until channel 2 counted down `ticks` PIT ticks.
+#[allow(clippy::cast_possible_truncation)]
+fn busy_wait_ticks(ticks: u64) {
+    let ticks = ticks.clamp(1, 0xFFFF) as u16;
+    let _guard = CHANNEL_2_LOCK.lock();
+
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut channel_2 = Port::<u8>::new(CHANNEL_2_PORT);
+
+    unsafe {
+        // Enable the gate, disable the speaker
+        let value = speaker.read();
+        speaker.write((value & !0b10) | 0b1);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel_2.write(ticks as u8);
+        channel_2.write((ticks >> 8) as u8);
+
+        // Output goes high once the count reaches zero
+        while speaker.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+
+        speaker.write(value);
+    }
+}
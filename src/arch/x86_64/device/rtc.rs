@@ -0,0 +1,143 @@
+//! CMOS real-time clock.
+//!
+//! Only read once at boot to set the wall clock.
+use crate::{arch::x86_64::acpi, time::wall::DateTime};
+use x86_64::instructions::port::Port;
+
+const ADDRESS_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Keeps NMIs enabled while selecting a register.
+const NMI_ENABLE: u8 = 0;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Offset of the century register index in the data of the ACPI `FACP`
+/// table.
+const FADT_CENTURY_OFFSET: usize = 72;
+/// Used if the firmware doesn't tell where the century is.
+const DEFAULT_CENTURY: u16 = 20;
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(ADDRESS_PORT).write(NMI_ENABLE | reg);
+        Port::<u8>::new(DATA_PORT).read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+/// Raw register values, still in whatever format the RTC uses.
+#[derive(PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl Registers {
+    fn read(century_register: Option<u8>) -> Self {
+        while update_in_progress() {
+            core::hint::spin_loop();
+        }
+        Registers {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: century_register.map_or(0, read_register),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Returns the index of the CMOS century register, if the firmware has one.
+fn century_register() -> Option<u8> {
+    let table = acpi::find_table(b"FACP")?;
+    match table.data().get(FADT_CENTURY_OFFSET) {
+        Some(&0) | None => None,
+        Some(&reg) => Some(reg),
+    }
+}
+
+/// Reads the current date and time.
+pub fn read() -> DateTime {
+    let century_register = century_register();
+
+    // An update could happen while reading, so read until two reads agree
+    let mut regs = Registers::read(century_register);
+    loop {
+        let next = Registers::read(century_register);
+        if next == regs {
+            break;
+        }
+        regs = next;
+    }
+
+    let status = read_register(REG_STATUS_B);
+    let binary = status & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = regs.hour & HOUR_PM != 0;
+    let mut hour = decode(regs.hour & !HOUR_PM);
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = if century_register.is_some() {
+        u16::from(decode(regs.century))
+    } else {
+        DEFAULT_CENTURY
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(decode(regs.year)),
+        month: decode(regs.month),
+        day: decode(regs.day),
+        hour,
+        minute: decode(regs.minute),
+        second: decode(regs.second),
+        nanosecond: 0,
+    }
+}
+
+/// Sets the wall clock from the RTC.
+///
+/// Should be called after `acpi::init`, so the century is known.
+pub fn init() {
+    let now = read();
+    match crate::time::wall::set(now) {
+        Ok(()) => log::info!(
+            "RTC reports {} UTC ({})",
+            now,
+            now.weekday_name().unwrap_or("?")
+        ),
+        Err(error) => log::warn!("RTC reports {} UTC, not using it: {:?}", now, error),
+    }
+}
@@ -0,0 +1,371 @@
+//! x87, SSE and AVX register state.
+//!
+//! Kernel code is built without SSE, so the extended state belongs to
+//! threads and to `with_fpu` sections of kernel code that want SIMD. It is
+//! saved with `XSAVE` if the CPU has it and `FXSAVE` otherwise.
+//!
+//! When switching threads, the state of the next thread is either restored
+//! eagerly, or lazily on its first FPU instruction: `CR0.TS` makes that raise
+//! `#NM`, whose handler restores it. Eager switching is used with `XSAVE`,
+//! which skips components that are in their initial state.
+use super::{
+    cpuid::{self, Feature},
+    woint,
+};
+use crate::percpu::per_cpu;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::{
+    arch::x86_64::__cpuid_count,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+const FXSAVE_SIZE: usize = 512;
+/// `XSAVE` needs a 64 byte aligned area, `FXSAVE` a 16 byte aligned one.
+const AREA_ALIGN: usize = 64;
+const LEAF_XSAVE: u32 = 0xD;
+
+const XCR0_X87: u64 = 1;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+/// Opmask, upper halves of ZMM0-15 and ZMM16-31, only usable together.
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// Offsets of the x87 control word and `MXCSR` in the legacy area.
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// All x87 exceptions masked, 64 bit precision.
+const DEFAULT_FCW: u16 = 0x037F;
+/// All SSE exceptions masked.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// When the state of a thread is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// When the thread is switched in.
+    Eager,
+    /// When the thread first uses the FPU after being switched in.
+    Lazy,
+}
+
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static XCR0: AtomicU64 = AtomicU64::new(0);
+static EAGER: AtomicBool = AtomicBool::new(false);
+/// Clean state loaded for `with_fpu` sections.
+static INITIAL: Once<FpuState> = Once::new();
+
+per_cpu! {
+    /// Area of the state whose registers are loaded, or null.
+    static LOADED: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+    /// Area of the state to restore on the next `#NM`, or null.
+    static PENDING: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+    /// Whether the CPU is in a `with_fpu` section.
+    static IN_SECTION: AtomicBool = AtomicBool::new(false);
+}
+
+/// Saved x87, SSE and AVX registers of a thread.
+#[allow(clippy::module_name_repetitions)]
+pub struct FpuState {
+    area: NonNull<u8>,
+}
+
+// The area is only accessed through `&mut FpuState` or while it is loaded
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    /// Creates a state with all registers cleared and all exceptions masked.
+    ///
+    /// # Panics
+    /// Panics if the FPU has not been initialized.
+    pub fn new() -> Self {
+        let initial = INITIAL.get().expect("FPU not initialized");
+        let state = Self::zeroed();
+        unsafe {
+            ptr::copy_nonoverlapping(initial.area.as_ptr(), state.area.as_ptr(), area_size());
+        }
+        state
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(area_size(), AREA_ALIGN).expect("invalid FPU area size")
+    }
+
+    fn zeroed() -> Self {
+        let layout = Self::layout();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        FpuState { area }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        let area = self.area.as_ptr();
+        woint(|| {
+            let _ = LOADED.get().compare_exchange(
+                area,
+                ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            let _ = PENDING.get().compare_exchange(
+                area,
+                ptr::null_mut(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        });
+        unsafe { dealloc(area, Self::layout()) };
+    }
+}
+
+/// Saves the registers into `area`.
+unsafe fn save(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        xsave(area);
+    } else {
+        fxsave(area);
+    }
+}
+
+/// Loads the registers from `area`.
+unsafe fn restore(area: *const u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        xrstor(area);
+    } else {
+        fxrstor(area);
+    }
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xsave(area: *mut u8) {
+    asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xrstor(area: *const u8) {
+    asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxsave(area: *mut u8) {
+    asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxrstor(area: *const u8) {
+    asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    #[allow(clippy::cast_possible_truncation)]
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    asm!("xsetbv", in("ecx") xcr, in("eax") low, in("edx") high, options(nomem, nostack));
+}
+
+fn clear_task_switched() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+}
+
+fn set_task_switched() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+}
+
+/// Returns the size of the save area in bytes.
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns when thread states are restored.
+pub fn strategy() -> Strategy {
+    if EAGER.load(Ordering::Relaxed) {
+        Strategy::Eager
+    } else {
+        Strategy::Lazy
+    }
+}
+
+/// Enables the FPU, SSE and, if available, `XSAVE` and AVX on the current
+/// CPU. FPU instructions trap afterwards until a state is loaded.
+unsafe fn configure() {
+    Cr0::update(|flags| {
+        flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+        flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+    });
+    Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+        xsetbv(0, XCR0.load(Ordering::Relaxed));
+    }
+    set_task_switched();
+}
+
+/// Sizes the save area, configures the BSP and picks the switching strategy.
+///
+/// # Safety
+/// Must only be called once, on the BSP, after the heap has been
+/// initialized.
+pub unsafe fn init() {
+    let use_xsave = cpuid::has(Feature::Xsave);
+    if use_xsave {
+        let leaf = __cpuid_count(LEAF_XSAVE, 0);
+        let supported = u64::from(leaf.eax) | (u64::from(leaf.edx) << 32);
+        let mut wanted = XCR0_X87 | XCR0_SSE;
+        if cpuid::has(Feature::Avx) {
+            wanted |= XCR0_AVX;
+        }
+        if cpuid::has(Feature::Avx512f) {
+            wanted |= XCR0_AVX512;
+        }
+        XCR0.store(supported & wanted, Ordering::Relaxed);
+    }
+    USE_XSAVE.store(use_xsave, Ordering::Relaxed);
+    EAGER.store(use_xsave, Ordering::Relaxed);
+    configure();
+
+    // The size depends on the components enabled in `XCR0`
+    if use_xsave {
+        let size = __cpuid_count(LEAF_XSAVE, 0).ebx as usize;
+        AREA_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    // An all zero `XSAVE` header puts every component in its initial state,
+    // except for `MXCSR` which is always loaded
+    let initial = FpuState::zeroed();
+    ptr::write(initial.area.as_ptr().add(FCW_OFFSET).cast(), DEFAULT_FCW);
+    ptr::write(
+        initial.area.as_ptr().add(MXCSR_OFFSET).cast(),
+        DEFAULT_MXCSR,
+    );
+    INITIAL.call_once(|| initial);
+
+    log::info!(
+        "FPU: {} byte {} area, XCR0={:#x}, {:?} switching",
+        area_size(),
+        if use_xsave { "XSAVE" } else { "FXSAVE" },
+        XCR0.load(Ordering::Relaxed),
+        strategy()
+    );
+}
+
+/// Configures an application processor like the BSP.
+///
+/// # Safety
+/// Must be called once per AP, after `init`.
+pub unsafe fn init_ap() {
+    configure();
+}
+
+/// Restores the pending state, if any. Returns `false` if there is none.
+fn load_pending() -> bool {
+    let pending = PENDING.get().swap(ptr::null_mut(), Ordering::Relaxed);
+    if pending.is_null() {
+        return false;
+    }
+    clear_task_switched();
+    unsafe { restore(pending) };
+    LOADED.get().store(pending, Ordering::Relaxed);
+    true
+}
+
+/// Handles `#NM` by restoring the state of the current thread. Returns
+/// `false` if there is no state to restore, meaning kernel code used the FPU
+/// outside of `with_fpu`.
+pub fn handle_device_not_available() -> bool {
+    load_pending()
+}
+
+/// Saves the registers of the thread being switched away from.
+///
+/// # Safety
+/// Must be called with interrupts disabled, before `switch_in`.
+pub unsafe fn switch_out(state: &mut FpuState) {
+    let loaded = LOADED.get().swap(ptr::null_mut(), Ordering::Relaxed);
+    // Lazily switched threads that never used the FPU have nothing to save
+    if loaded == state.area.as_ptr() {
+        save(loaded);
+    }
+    PENDING.get().store(ptr::null_mut(), Ordering::Relaxed);
+    set_task_switched();
+}
+
+/// Makes `state` the state of the current thread, restoring it now or on its
+/// first FPU instruction depending on the strategy.
+///
+/// # Safety
+/// Must be called with interrupts disabled. `state` must outlive the time it
+/// is switched in, and must not be switched in on another CPU.
+pub unsafe fn switch_in(state: &mut FpuState) {
+    PENDING.get().store(state.area.as_ptr(), Ordering::Relaxed);
+    if EAGER.load(Ordering::Relaxed) {
+        load_pending();
+    }
+}
+
+/// Runs `f` with the FPU, SSE and AVX registers usable by kernel code. They
+/// start out cleared, the state of the current thread is kept aside.
+///
+/// The kernel is compiled without SSE, so `f` has to use assembly or
+/// functions with `#[target_feature]` to actually use them. Runs with
+/// interrupts disabled, nested sections share the registers.
+///
+/// # Panics
+/// Panics if the FPU has not been initialized.
+pub fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+    woint(|| {
+        let section = IN_SECTION.get();
+        if section.load(Ordering::Relaxed) {
+            return f();
+        }
+        let initial = INITIAL.get().expect("FPU not initialized");
+        section.store(true, Ordering::Relaxed);
+
+        clear_task_switched();
+        let loaded = LOADED.get().swap(ptr::null_mut(), Ordering::Relaxed);
+        if !loaded.is_null() {
+            unsafe { save(loaded) };
+            PENDING.get().store(loaded, Ordering::Relaxed);
+        }
+        unsafe { restore(initial.area.as_ptr()) };
+
+        let result = f();
+
+        set_task_switched();
+        if EAGER.load(Ordering::Relaxed) {
+            load_pending();
+        }
+        section.store(false, Ordering::Relaxed);
+        result
+    })
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_with_fpu() {
+    #[target_feature(enable = "sse2")]
+    unsafe fn double(value: u64) -> u64 {
+        let result: u64;
+        asm!(
+            "movq {1}, {0}",
+            "paddq {1}, {1}",
+            "movq {0}, {1}",
+            inout(reg) value => result,
+            out(xmm_reg) _,
+            options(nomem, nostack)
+        );
+        result
+    }
+
+    serial_print!("test_with_fpu... ");
+    assert_eq!(with_fpu(|| unsafe { double(21) }), 42);
+    // Nested sections work too
+    assert_eq!(with_fpu(|| with_fpu(|| unsafe { double(2) })), 4);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,650 @@
+//! GDB Remote Serial Protocol stub on COM2.
+//!
+//! Breakpoint and debug exceptions enter the stub, which then serves
+//! requests from GDB until it continues or steps. Other CPUs keep running
+//! while stopped. Attach with `target remote` to the chardev QEMU exposes
+//! COM2 on, e.g. `-serial stdio -serial tcp::1234,server`.
+use super::{
+    device::pic8259::InterruptIndex,
+    interrupts::{
+        exceptions,
+        irq::{self, IrqResult},
+        TrapFrame,
+    },
+    memory,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    VirtAddr,
+};
+
+pub const COM2_ADDR: u16 = 0x02F8;
+
+/// Largest packet accepted or sent, advertised to GDB.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xCC;
+const TRAP_FLAG: u64 = 1 << 8;
+const CTRL_C: u8 = 0x03;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Registers in the `g` packet: 16 general purpose registers, rip, eflags
+/// and 6 segment registers.
+const REGISTER_COUNT: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+/// Initializes COM2 and enters the stub, waiting for GDB to connect.
+pub fn init() {
+    STUB.lock().port.init();
+    irq::register(InterruptIndex::Com2.as_u8(), interrupt_handler);
+    ENABLED.store(true, Ordering::Release);
+
+    log::info!("waiting for GDB on COM2");
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Whether the stub handles breakpoint and debug exceptions.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Handles a breakpoint or debug exception, returns once GDB resumes.
+pub fn handle_exception(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    // Report our breakpoints at their address instead of after them
+    if frame.vector == exceptions::BREAKPOINT
+        && stub.breakpoints.contains(frame.rip.wrapping_sub(1))
+    {
+        frame.rip -= 1;
+    }
+    stub.run(frame, SIGTRAP);
+}
+
+/// Stops when GDB sends a Ctrl-C.
+fn interrupt_handler(frame: &mut TrapFrame) -> IrqResult {
+    let mut stub = STUB.lock();
+    if stub.port.receive() == CTRL_C {
+        stub.run(frame, SIGINT);
+    }
+    IrqResult::Handled
+}
+
+/// What to do after a packet has been handled.
+enum Action {
+    Reply,
+    Continue,
+    Step,
+    /// Reply, then continue.
+    Detach,
+}
+
+struct Stub {
+    port: SerialPort,
+    breakpoints: Breakpoints,
+    packet: [u8; PACKET_SIZE],
+    response: Response,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Stub {
+            port: unsafe { SerialPort::new(COM2_ADDR) },
+            breakpoints: Breakpoints::new(),
+            packet: [0; PACKET_SIZE],
+            response: Response::new(),
+        }
+    }
+
+    /// Reports the stop to GDB and serves requests until it resumes.
+    fn run(&mut self, frame: &mut TrapFrame, signal: u8) {
+        frame.rflags &= !TRAP_FLAG;
+        self.response.clear();
+        self.response.push_stop(signal);
+        self.send_response();
+
+        loop {
+            let len = self.receive_packet();
+            self.response.clear();
+            let action = handle_packet(
+                &self.packet[..len],
+                frame,
+                &mut self.breakpoints,
+                &mut self.response,
+            );
+            match action {
+                Action::Reply => self.send_response(),
+                Action::Continue => return,
+                Action::Step => {
+                    frame.rflags |= TRAP_FLAG;
+                    return;
+                }
+                Action::Detach => {
+                    self.send_response();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads a `$packet#checksum` into `self.packet` and acknowledges it.
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            while self.port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let byte = self.port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    self.packet[len] = byte;
+                    len += 1;
+                }
+            }
+            let checksum = [self.port.receive(), self.port.receive()];
+
+            if parse_hex(&checksum) == Some(u64::from(sum)) {
+                self.port.send(b'+');
+                return len;
+            }
+            self.port.send(b'-');
+        }
+    }
+
+    /// Sends `self.response` as a packet until GDB acknowledges it.
+    fn send_response(&mut self) {
+        let data = self.response.as_bytes();
+        let sum = checksum(data);
+        loop {
+            self.port.send(b'$');
+            for byte in data {
+                self.port.send(*byte);
+            }
+            self.port.send(b'#');
+            self.port.send(HEX[usize::from(sum >> 4)]);
+            self.port.send(HEX[usize::from(sum & 0xF)]);
+
+            if self.port.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn handle_packet(
+    packet: &[u8],
+    frame: &mut TrapFrame,
+    breakpoints: &mut Breakpoints,
+    response: &mut Response,
+) -> Action {
+    let (&command, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Reply,
+    };
+
+    match command {
+        b'?' => response.push_stop(SIGTRAP),
+        b'g' => {
+            for n in 0..REGISTER_COUNT {
+                let (value, size) = read_register(frame, n);
+                response.push_hex(&value.to_le_bytes()[..size]);
+            }
+        }
+        b'G' => {
+            let mut offset = 0;
+            for n in 0..REGISTER_COUNT {
+                let size = read_register(frame, n).1 * 2;
+                match args.get(offset..offset + size).and_then(parse_hex_le) {
+                    Some(value) => write_register(frame, n, value),
+                    None => break,
+                }
+                offset += size;
+            }
+            response.push_str("OK");
+        }
+        b'p' => match parse_hex(args) {
+            Some(n) if (n as usize) < REGISTER_COUNT => {
+                let (value, size) = read_register(frame, n as usize);
+                response.push_hex(&value.to_le_bytes()[..size]);
+            }
+            _ => response.push_error(1),
+        },
+        b'P' => match split_at_byte(args, b'=') {
+            Some((n, value)) => match (parse_hex(n), parse_hex_le(value)) {
+                (Some(n), Some(value)) if (n as usize) < REGISTER_COUNT => {
+                    write_register(frame, n as usize, value);
+                    response.push_str("OK");
+                }
+                _ => response.push_error(1),
+            },
+            None => response.push_error(1),
+        },
+        b'm' => match parse_address_length(args) {
+            Some((address, length)) if is_accessible(address, length) => {
+                for i in 0..length {
+                    let byte = unsafe { ((address + i) as *const u8).read_volatile() };
+                    response.push_hex(&[byte]);
+                }
+            }
+            _ => response.push_error(SIGSEGV),
+        },
+        b'M' => {
+            let target = split_at_byte(args, b':')
+                .and_then(|(target, data)| Some((parse_address_length(target)?, data)));
+            match target {
+                Some(((address, length), data))
+                    if is_accessible(address, length) && data.len() as u64 == length * 2 =>
+                {
+                    for (i, byte) in data.chunks(2).enumerate() {
+                        let byte = parse_hex(byte).unwrap_or(0) as u8;
+                        unsafe { write_text(address + i as u64, byte) };
+                    }
+                    response.push_str("OK");
+                }
+                _ => response.push_error(SIGSEGV),
+            }
+        }
+        b'Z' | b'z' => {
+            // Only software breakpoints (type 0) are supported, others get an
+            // empty response
+            let address = split_at_byte(args, b',')
+                .filter(|(kind, _)| *kind == b"0")
+                .and_then(|(_, rest)| split_at_byte(rest, b','))
+                .and_then(|(address, _)| parse_hex(address));
+            if let Some(address) = address {
+                let ok = if command == b'Z' {
+                    breakpoints.insert(address)
+                } else {
+                    breakpoints.remove(address)
+                };
+                if ok {
+                    response.push_str("OK");
+                } else {
+                    response.push_error(1);
+                }
+            }
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(args) {
+                frame.rip = address;
+            }
+            // The reply is sent at the next stop
+            return if command == b'c' {
+                Action::Continue
+            } else {
+                Action::Step
+            };
+        }
+        b'D' | b'k' => {
+            breakpoints.clear();
+            response.push_str("OK");
+            return Action::Detach;
+        }
+        b'H' => response.push_str("OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                response.push_str("PacketSize=1000;swbreak+");
+            } else if args == b"Attached" {
+                response.push_str("1");
+            } else if args == b"C" {
+                response.push_str("QC1");
+            } else if args == b"fThreadInfo" {
+                response.push_str("m1");
+            } else if args == b"sThreadInfo" {
+                response.push_str("l");
+            }
+        }
+        // Unsupported packets get an empty response
+        _ => {}
+    }
+    Action::Reply
+}
+
+/// Returns a register in the `g` packet order and its size in bytes.
+fn read_register(frame: &TrapFrame, n: usize) -> (u64, usize) {
+    let value = match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        RIP => frame.rip,
+        EFLAGS => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        // Data segments aren't saved, they are all null
+        _ => 0,
+    };
+    (value, if n <= RIP { 8 } else { 4 })
+}
+
+/// Writes a register in the `g` packet order. Writes to unsaved registers
+/// are ignored.
+fn write_register(frame: &mut TrapFrame, n: usize, value: u64) {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        RIP => &mut frame.rip,
+        EFLAGS => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return,
+    };
+    *register = value;
+}
+
+/// Whether `length` bytes at `address` are mapped.
+fn is_accessible(address: u64, length: u64) -> bool {
+    const PAGE_SIZE: u64 = 4096;
+
+    let last = match address.checked_add(length.saturating_sub(1)) {
+        Some(last) if length > 0 => last,
+        _ => return false,
+    };
+    let mut page = address & !(PAGE_SIZE - 1);
+    while page <= last {
+        match VirtAddr::try_new(page) {
+            Ok(addr) if memory::is_mapped(addr) => {}
+            _ => return false,
+        }
+        page = match page.checked_add(PAGE_SIZE) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    true
+}
+
+/// Writes a byte, even if its page is read-only like kernel code.
+///
+/// # Safety
+/// `address` must be mapped, interrupts must be disabled.
+unsafe fn write_text(address: u64, byte: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    (address as *mut u8).write_volatile(byte);
+    Cr0::write(cr0);
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// Software breakpoints inserted by GDB.
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    const fn new() -> Self {
+        Breakpoints([None; MAX_BREAKPOINTS])
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        self.0.iter().flatten().any(|bp| bp.address == address)
+    }
+
+    fn insert(&mut self, address: u64) -> bool {
+        if self.contains(address) {
+            return true;
+        }
+        if !is_accessible(address, 1) {
+            return false;
+        }
+        match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => unsafe {
+                let original = (address as *const u8).read_volatile();
+                write_text(address, INT3);
+                *slot = Some(Breakpoint { address, original });
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, address: u64) -> bool {
+        for slot in &mut self.0 {
+            if let Some(bp) = *slot {
+                if bp.address == address {
+                    unsafe { write_text(address, bp.original) };
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn clear(&mut self) {
+        for slot in &mut self.0 {
+            if let Some(bp) = slot.take() {
+                unsafe { write_text(bp.address, bp.original) };
+            }
+        }
+    }
+}
+
+/// Packet data being built. Data that doesn't fit is dropped.
+struct Response {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Response {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.push(HEX[usize::from(byte >> 4)]);
+            self.push(HEX[usize::from(byte & 0xF)]);
+        }
+    }
+
+    fn push_stop(&mut self, signal: u8) {
+        self.push(b'S');
+        self.push_hex(&[signal]);
+    }
+
+    fn push_error(&mut self, code: u8) {
+        self.push(b'E');
+        self.push_hex(&[code]);
+    }
+}
+
+/// Returns the checksum of packet data, the sum of its bytes modulo 256.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_digit(byte: u8) -> Option<u64> {
+    char::from(byte).to_digit(16).map(u64::from)
+}
+
+/// Parses a big endian hex number.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0, |value, digit| Some((value << 4) | hex_digit(*digit)?))
+}
+
+/// Parses hex encoded bytes in target (little endian) order.
+fn parse_hex_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || digits.len() % 2 != 0 {
+        return None;
+    }
+    digits.chunks(2).rev().try_fold(0, |value, byte| {
+        Some((value << 8) | (hex_digit(byte[0])? << 4) | hex_digit(byte[1])?)
+    })
+}
+
+/// Parses `address,length`.
+#[allow(clippy::cast_possible_truncation)]
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let (address, length) = split_at_byte(args, b',')?;
+    let length = parse_hex(length)?;
+    if length as usize > PACKET_SIZE / 2 {
+        return None;
+    }
+    Some((parse_hex(address)?, length))
+}
+
+/// Splits `bytes` at the first `separator`.
+fn split_at_byte(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Handles `packet` with a zeroed frame and returns the action and response.
+#[cfg(test)]
+fn test_packet(packet: &[u8], breakpoints: &mut Breakpoints) -> (Action, Response) {
+    // All fields are plain integers
+    let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+    let mut response = Response::new();
+    let action =
+        crate::arch::woint(|| handle_packet(packet, &mut frame, breakpoints, &mut response));
+    (action, response)
+}
+
+#[test_case]
+fn test_gdb_checksum_and_hex() {
+    serial_print!("test_gdb_checksum_and_hex... ");
+    // From the GDB documentation: `$qSupported#37`
+    assert_eq!(checksum(b"qSupported"), 0x37);
+    assert_eq!(checksum(b""), 0);
+    assert_eq!(parse_hex(b"1f"), Some(0x1F));
+    assert_eq!(parse_hex(b"ffffffffffffffff"), Some(u64::MAX));
+    assert_eq!(parse_hex(b"10000000000000000"), None);
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"xy"), None);
+    assert_eq!(parse_hex_le(b"3412"), Some(0x1234));
+    assert_eq!(parse_hex_le(b"123"), None);
+    assert_eq!(parse_address_length(b"1000,4"), Some((0x1000, 4)));
+    assert_eq!(parse_address_length(b"1000"), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gdb_memory_packets() {
+    static mut MEMORY: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    serial_print!("test_gdb_memory_packets... ");
+    let address = unsafe { MEMORY.as_ptr() } as u64;
+    let mut breakpoints = Breakpoints::new();
+
+    let read = alloc::format!("m{:x},4", address);
+    let (action, response) = test_packet(read.as_bytes(), &mut breakpoints);
+    assert!(matches!(action, Action::Reply));
+    assert_eq!(response.as_bytes(), b"12345678");
+
+    let write = alloc::format!("M{:x},2:abcd", address);
+    let (_, response) = test_packet(write.as_bytes(), &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(unsafe { MEMORY }, [0xAB, 0xCD, 0x56, 0x78]);
+
+    // Data that doesn't match the length is rejected
+    let write = alloc::format!("M{:x},2:ab", address);
+    let (_, response) = test_packet(write.as_bytes(), &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"E0b");
+    let (_, response) = test_packet(b"m0,1", &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"E0b");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gdb_breakpoint_packets() {
+    static mut CODE: [u8; 1] = [0x90];
+
+    serial_print!("test_gdb_breakpoint_packets... ");
+    let address = unsafe { CODE.as_ptr() } as u64;
+    let mut breakpoints = Breakpoints::new();
+
+    let insert = alloc::format!("Z0,{:x},1", address);
+    let (_, response) = test_packet(insert.as_bytes(), &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert!(breakpoints.contains(address));
+    assert_eq!(unsafe { CODE[0] }, INT3);
+
+    let remove = alloc::format!("z0,{:x},1", address);
+    let (_, response) = test_packet(remove.as_bytes(), &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"OK");
+    assert_eq!(unsafe { CODE[0] }, 0x90);
+
+    // Hardware breakpoints aren't supported
+    let insert = alloc::format!("Z1,{:x},1", address);
+    let (_, response) = test_packet(insert.as_bytes(), &mut breakpoints);
+    assert_eq!(response.as_bytes(), b"");
+    serial_println!("[ok]");
+}
@@ -1,9 +1,12 @@
+use alloc::boxed::Box;
 use spin::Once;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Size of the double fault stacks, big enough to format a crash report.
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
 
 struct Selectors {
     code_selector: SegmentSelector,
@@ -13,34 +16,62 @@ struct Selectors {
 static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
 static TSS: Once<TaskStateSegment> = Once::new();
 
+fn new_tss(double_fault_stack_end: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_end;
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
+}
+
+unsafe fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation;
+
+    gdt.0.load();
+    segmentation::set_cs(gdt.1.code_selector);
+    // The GDT has no data segments, use the null selector
+    segmentation::load_ss(SegmentSelector(0));
+    segmentation::load_ds(SegmentSelector(0));
+    segmentation::load_es(SegmentSelector(0));
+    x86_64::instructions::tables::load_tss(gdt.1.tss_selector);
+}
+
 /// Initialize the GDT, TSS and CS.
 ///
 /// # Safety
 /// Must only be called once.
 pub unsafe fn init() {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let tss = new_tss({
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
         let stack_start = VirtAddr::from_ptr(&STACK);
-        stack_start + STACK_SIZE
-    };
-
-    let mut gdt = GlobalDescriptorTable::new();
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.call_once(|| tss)));
-    let gdt = GDT.call_once(|| {
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        stack_start + DOUBLE_FAULT_STACK_SIZE
     });
 
-    gdt.0.load();
-    x86_64::instructions::segmentation::set_cs(gdt.1.code_selector);
-    x86_64::instructions::tables::load_tss(gdt.1.tss_selector);
+    load(GDT.call_once(|| new_gdt(TSS.call_once(|| tss))));
+}
+
+/// Initialize a GDT and TSS for an application processor, and load them.
+/// These are allocated on the heap and never freed.
+///
+/// # Safety
+/// Must only be called once per application processor.
+pub unsafe fn init_ap() {
+    let stack: &'static mut [u8] =
+        Box::leak(alloc::vec![0; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let stack_end = VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(new_tss(stack_end)));
+    load(Box::leak(Box::new(new_gdt(tss))));
 }
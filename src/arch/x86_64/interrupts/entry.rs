@@ -0,0 +1,143 @@
+//! Assembly entry stubs for interrupt vectors.
+//!
+//! Every stub pushes a dummy error code (unless the CPU pushed one) and its
+//! vector, then jumps to a common routine that saves all general purpose
+//! registers and calls `trap_entry` with a pointer to the resulting
+//! `TrapFrame`. Stubs are `STUB_SIZE` bytes apart, so the stub of a vector
+//! can be found without a table.
+use super::{exceptions, irq, DEPTH, INTERRUPT_COUNT};
+use core::sync::atomic::Ordering;
+
+const STUB_SIZE: usize = 16;
+
+global_asm!(
+    r#"
+.section .text
+.balign 16
+trap_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+
+    mov %rsp, %rdi
+    cld
+    call trap_entry
+
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+
+    // Skip the vector and error code
+    add $16, %rsp
+    iretq
+
+.balign 16
+.global trap_stubs
+trap_stubs:
+.set vector, 0
+.rept 256
+    .balign 16
+    // Exceptions 8, 10-14, 17, 21, 29 and 30 push an error code. The shift
+    // count is masked by the assembler, so larger vectors are checked first
+    .if vector >= 32 || ((0x60227D00 >> vector) & 1) == 0
+    push $0
+    .endif
+    push $vector
+    jmp trap_common
+    .set vector, vector + 1
+.endr
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static trap_stubs: u8;
+}
+
+/// Register state saved on interrupt entry.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Returns the entry stub of `vector` as any of the IDT handler function
+/// types, to be put in the IDT.
+///
+/// # Safety
+/// `F` must be one of the handler function types in `x86_64::structures::idt`.
+///
+/// # Panics
+/// Panics if `vector` is out of range.
+pub unsafe fn stub<F: Copy>(vector: usize) -> F {
+    assert!(vector < 256);
+    assert_eq!(core::mem::size_of::<F>(), core::mem::size_of::<usize>());
+    let addr = &trap_stubs as *const u8 as usize + vector * STUB_SIZE;
+    // The stubs aren't `x86-interrupt` functions, but the IDT only cares
+    // about their address
+    core::mem::transmute_copy::<usize, F>(&addr)
+}
+
+#[no_mangle]
+extern "C" fn trap_entry(frame: &mut TrapFrame) {
+    let depth = DEPTH.get();
+    depth.fetch_add(1, Ordering::Relaxed);
+    if frame.vector < 32 {
+        exceptions::handle(frame);
+    } else {
+        INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed);
+        irq::dispatch(frame);
+    }
+    depth.fetch_sub(1, Ordering::Relaxed);
+
+    // Interrupts are acknowledged by now, so other threads can take them
+    if frame.vector >= 32 && depth.load(Ordering::Relaxed) == 0 {
+        crate::thread::preempt();
+    }
+}
@@ -0,0 +1,206 @@
+//! CPU exception handlers.
+//!
+//! Watchpoint hits are reported, breakpoints and other debug traps are logged
+//! (or handed to the GDB stub) and execution continues, every other exception
+//! is fatal and panics with a `CrashReport`. `#NM` restores lazily switched
+//! FPU state.
+use super::TrapFrame;
+use crate::{backtrace::Backtrace, symbols::Symbolized};
+use core::fmt;
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::PageFaultErrorCode,
+};
+
+/// Names and mnemonics of the architectural exceptions, indexed by vector.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("DIVIDE ERROR", "#DE"),
+    ("DEBUG", "#DB"),
+    ("NON-MASKABLE INTERRUPT", "NMI"),
+    ("BREAKPOINT", "#BP"),
+    ("OVERFLOW", "#OF"),
+    ("BOUND RANGE EXCEEDED", "#BR"),
+    ("INVALID OPCODE", "#UD"),
+    ("DEVICE NOT AVAILABLE", "#NM"),
+    ("DOUBLE FAULT", "#DF"),
+    ("COPROCESSOR SEGMENT OVERRUN", "-"),
+    ("INVALID TSS", "#TS"),
+    ("SEGMENT NOT PRESENT", "#NP"),
+    ("STACK SEGMENT FAULT", "#SS"),
+    ("GENERAL PROTECTION FAULT", "#GP"),
+    ("PAGE FAULT", "#PF"),
+    ("RESERVED", "-"),
+    ("X87 FLOATING POINT", "#MF"),
+    ("ALIGNMENT CHECK", "#AC"),
+    ("MACHINE CHECK", "#MC"),
+    ("SIMD FLOATING POINT", "#XM"),
+    ("VIRTUALIZATION", "#VE"),
+    ("CONTROL PROTECTION", "#CP"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("RESERVED", "-"),
+    ("HYPERVISOR INJECTION", "#HV"),
+    ("VMM COMMUNICATION", "#VC"),
+    ("SECURITY EXCEPTION", "#SX"),
+    ("RESERVED", "-"),
+];
+
+pub const DEBUG: u64 = 1;
+pub const BREAKPOINT: u64 = 3;
+pub const DEVICE_NOT_AVAILABLE: u64 = 7;
+pub const INVALID_TSS: u64 = 10;
+pub const SEGMENT_NOT_PRESENT: u64 = 11;
+pub const STACK_SEGMENT_FAULT: u64 = 12;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+
+/// Returns the name and mnemonic of an exception vector.
+#[allow(clippy::cast_possible_truncation)]
+pub fn name(vector: u64) -> (&'static str, &'static str) {
+    EXCEPTIONS
+        .get(vector as usize)
+        .copied()
+        .unwrap_or(("UNKNOWN", "-"))
+}
+
+/// Decoded error code of `#TS`, `#NP`, `#SS` and `#GP`.
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    /// Whether the exception was caused by an event external to the program.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// Descriptor table the selector refers to.
+    pub fn table(self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        }
+    }
+
+    /// Index of the selector in its descriptor table.
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1FFF
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not selector related");
+        }
+        write!(
+            f,
+            "{} index {}, external: {}",
+            self.table(),
+            self.index(),
+            self.external()
+        )
+    }
+}
+
+/// Human readable dump of an exception and the register state at the time
+/// it happened.
+pub struct CrashReport<'a> {
+    frame: &'a TrapFrame,
+}
+
+impl<'a> CrashReport<'a> {
+    pub fn new(frame: &'a TrapFrame) -> Self {
+        CrashReport { frame }
+    }
+
+    fn fmt_error_code(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.frame.error_code;
+        match self.frame.vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                writeln!(f, "Error Code: {:#x} ({})", code, SelectorErrorCode(code))
+            }
+            PAGE_FAULT => {
+                writeln!(
+                    f,
+                    "Error Code: {:#x} ({:?})",
+                    code,
+                    PageFaultErrorCode::from_bits_truncate(code)
+                )?;
+                writeln!(f, "Accessed Address: {:?}", Cr2::read())
+            }
+            _ => writeln!(f, "Error Code: {:#x}", code),
+        }
+    }
+}
+
+impl fmt::Display for CrashReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = self.frame;
+        let (name, mnemonic) = name(r.vector);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let rip = Symbolized(r.rip as usize);
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {}) at {}",
+            name, mnemonic, r.vector, rip
+        )?;
+        self.fmt_error_code(f)?;
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            r.rsi, r.rdi, r.rbp, r.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016x} RFL={:016x} CS={:04x} SS={:04x}",
+            r.rip, r.rflags, r.cs, r.ss
+        )?;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+        #[allow(clippy::cast_possible_truncation)]
+        let backtrace = Backtrace::from_frame(r.rip as usize, r.rbp as usize);
+        write!(f, "{}", backtrace)
+    }
+}
+
+/// Handles the exception in `frame`.
+pub(super) fn handle(frame: &mut TrapFrame) {
+    match frame.vector {
+        DEBUG if crate::arch::x86_64::watchpoint::handle(frame) => {}
+        DEVICE_NOT_AVAILABLE if crate::arch::x86_64::fpu::handle_device_not_available() => {}
+        BREAKPOINT | DEBUG => {
+            #[cfg(feature = "gdb")]
+            if crate::arch::x86_64::gdb::is_enabled() {
+                return crate::arch::x86_64::gdb::handle_exception(frame);
+            }
+            log::info!("{}", CrashReport::new(frame));
+        }
+        _ => panic!("{}", CrashReport::new(frame)),
+    }
+}
@@ -0,0 +1,254 @@
+//! Dynamic interrupt handler registry.
+//!
+//! Drivers request a vector (or claim a fixed one, like the legacy PIC
+//! lines), and attach any number of handlers to it. All handlers of a vector
+//! are called in registration order, and the interrupt is acknowledged
+//! automatically afterwards.
+//!
+//! Handlers run in interrupt context with interrupts disabled, so they must
+//! not block or allocate. They may unregister themselves, the handler is then
+//! only marked as removed and freed by the next `register` or `unregister`
+//! outside of interrupt context.
+use super::entry::TrapFrame;
+use crate::arch::x86_64::{
+    device::{apic, pic8259},
+    woint,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
+
+/// Vectors below this are reserved for CPU exceptions.
+const FIRST_IRQ_VECTOR: u8 = 32;
+
+/// Result of an interrupt handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    /// The interrupt was raised by the handler's device.
+    Handled,
+    /// The interrupt was not meant for this handler, used for shared vectors.
+    NotHandled,
+}
+
+/// Plain function handler, called with the context pointer given on
+/// registration.
+pub type IrqHandlerFn = fn(context: *mut (), frame: &mut TrapFrame) -> IrqResult;
+
+/// Identifies a registered handler, used to unregister it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    /// Returns the vector the handler is registered on.
+    pub fn vector(self) -> u8 {
+        self.vector
+    }
+}
+
+enum HandlerKind {
+    Fn {
+        func: IrqHandlerFn,
+        context: *mut (),
+    },
+    Closure(Box<dyn Fn(&mut TrapFrame) -> IrqResult + Send + Sync>),
+}
+
+struct Handler {
+    id: u64,
+    kind: HandlerKind,
+    /// Set by `unregister`, the handler is freed later.
+    removed: AtomicBool,
+}
+
+// Context pointers are required to be usable from any CPU on registration
+unsafe impl Send for Handler {}
+unsafe impl Sync for Handler {}
+
+impl Handler {
+    fn call(&self, frame: &mut TrapFrame) -> IrqResult {
+        match &self.kind {
+            HandlerKind::Fn { func, context } => func(*context, frame),
+            HandlerKind::Closure(closure) => closure(frame),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: RwLock<Vec<Handler>> = RwLock::new(Vec::new());
+
+static HANDLERS: [RwLock<Vec<Handler>>; 256] = [NO_HANDLERS; 256];
+/// Bitmap of allocated vectors, exception vectors are always allocated.
+static ALLOCATED: Mutex<[u64; 4]> = Mutex::new([0xFFFF_FFFF, 0, 0, 0]);
+/// Number of interrupts no handler took care of.
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+fn is_allocated(allocated: &[u64; 4], vector: u8) -> bool {
+    allocated[vector as usize / 64] & (1 << (vector % 64)) != 0
+}
+
+fn set_allocated(allocated: &mut [u64; 4], vector: u8, value: bool) {
+    if value {
+        allocated[vector as usize / 64] |= 1 << (vector % 64);
+    } else {
+        allocated[vector as usize / 64] &= !(1 << (vector % 64));
+    }
+}
+
+/// Allocates an unused vector.
+pub fn request_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED.lock();
+    let vector = (FIRST_IRQ_VECTOR..=u8::MAX).find(|v| !is_allocated(&allocated, *v))?;
+    set_allocated(&mut allocated, vector, true);
+    Some(vector)
+}
+
+/// Allocates the given vector, for devices with fixed vectors.
+/// Returns `false` if it is already allocated.
+pub fn claim_vector(vector: u8) -> bool {
+    let mut allocated = ALLOCATED.lock();
+    if vector < FIRST_IRQ_VECTOR || is_allocated(&allocated, vector) {
+        return false;
+    }
+    set_allocated(&mut allocated, vector, true);
+    true
+}
+
+/// Frees the given vector, so it can be allocated again.
+///
+/// # Panics
+/// Panics if the vector still has handlers.
+pub fn free_vector(vector: u8) {
+    let in_use = HANDLERS[vector as usize]
+        .read()
+        .iter()
+        .any(|handler| !handler.removed.load(Ordering::Acquire));
+    assert!(
+        !in_use,
+        "freeing vector {} with registered handlers",
+        vector
+    );
+    if vector >= FIRST_IRQ_VECTOR {
+        set_allocated(&mut ALLOCATED.lock(), vector, false);
+    }
+}
+
+fn add_handler(vector: u8, kind: HandlerKind) -> HandlerId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handler = Handler {
+        id,
+        kind,
+        removed: AtomicBool::new(false),
+    };
+    prune(vector);
+    woint(|| HANDLERS[vector as usize].write().push(handler));
+    HandlerId { vector, id }
+}
+
+/// Frees the handlers of `vector` that were unregistered. Must not be
+/// called from interrupt context, the handlers are dropped here.
+fn prune(vector: u8) {
+    // Dropped at the end, with interrupts enabled
+    let _removed = woint(|| {
+        // Fails while handlers run, pruning then waits for the next call
+        let mut handlers = HANDLERS[vector as usize].try_write()?;
+        let (removed, kept): (Vec<_>, Vec<_>) = handlers
+            .drain(..)
+            .partition(|handler| handler.removed.load(Ordering::Acquire));
+        *handlers = kept;
+        Some(removed)
+    });
+}
+
+/// Attaches `handler` to `vector`.
+pub fn register(
+    vector: u8,
+    handler: impl Fn(&mut TrapFrame) -> IrqResult + Send + Sync + 'static,
+) -> HandlerId {
+    add_handler(vector, HandlerKind::Closure(Box::new(handler)))
+}
+
+/// Attaches `func` to `vector`, which will be called with `context`.
+///
+/// # Safety
+/// `context` must stay valid and be safe to use from any CPU until the
+/// handler is unregistered.
+pub unsafe fn register_fn(vector: u8, func: IrqHandlerFn, context: *mut ()) -> HandlerId {
+    add_handler(vector, HandlerKind::Fn { func, context })
+}
+
+/// Detaches a handler. Returns `false` if it was not registered.
+///
+/// Handlers may unregister themselves, but must not call this on another
+/// handler of the same vector.
+pub fn unregister(id: HandlerId) -> bool {
+    // A read lock, so a handler of this vector can call this
+    let found = woint(|| {
+        HANDLERS[id.vector as usize]
+            .read()
+            .iter()
+            .find(|handler| handler.id == id.id)
+            .map_or(false, |handler| {
+                !handler.removed.swap(true, Ordering::AcqRel)
+            })
+    });
+    // Handlers run with interrupts disabled and must not free anything
+    if x86_64::instructions::interrupts::are_enabled() {
+        prune(id.vector);
+    }
+    found
+}
+
+/// Returns how many interrupts arrived that no handler took care of.
+pub fn unhandled_count() -> u64 {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+/// Acknowledges the interrupt on the controller it came from.
+fn eoi(vector: u8) {
+    if pic8259::handles(vector) {
+        pic8259::notify_end_of_interrupt(vector);
+    } else if let Some(lapic) = apic::local_apic() {
+        lapic.eoi();
+    }
+}
+
+/// Calls all handlers of the vector in `frame`, then acknowledges it.
+#[allow(clippy::cast_possible_truncation)]
+pub(super) fn dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+
+    let mut handled = false;
+    for handler in HANDLERS[vector as usize].read().iter() {
+        if !handler.removed.load(Ordering::Acquire) {
+            handled |= handler.call(frame) == IrqResult::Handled;
+        }
+    }
+    if !handled {
+        // Logging could deadlock on the lock of the interrupted code
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    eoi(vector);
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_vector_allocation() {
+    serial_print!("test_vector_allocation... ");
+    let vector = request_vector().expect("no free vectors");
+    assert!(!claim_vector(vector));
+    let id = register(vector, |_| IrqResult::Handled);
+    assert!(unregister(id));
+    assert!(!unregister(id));
+    free_vector(vector);
+    assert!(claim_vector(vector));
+    free_vector(vector);
+    serial_println!("[ok]");
+}
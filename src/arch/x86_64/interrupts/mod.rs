@@ -0,0 +1,109 @@
+pub mod entry;
+pub mod exceptions;
+pub mod irq;
+
+pub use entry::TrapFrame;
+
+use super::{device::apic, gdt};
+use crate::percpu::per_cpu;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+static IDT: Once<InterruptDescriptorTable> = Once::new();
+
+per_cpu! {
+    /// How many nested interrupts and exceptions the CPU is handling.
+    static DEPTH: AtomicUsize = AtomicUsize::new(0);
+    /// How many interrupts the CPU has handled, exceptions not included.
+    static INTERRUPT_COUNT: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Whether the current CPU is handling an interrupt or exception.
+pub fn in_interrupt() -> bool {
+    DEPTH.get().load(Ordering::Relaxed) > 0
+}
+
+/// Returns how many interrupts the CPU with logical ID `cpu` has handled.
+pub fn interrupt_count(cpu: usize) -> u64 {
+    INTERRUPT_COUNT
+        .for_cpu(cpu)
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+fn build_idt() -> InterruptDescriptorTable {
+    /// Points the given IDT entries to the entry stubs of their vectors.
+    macro set_stubs($idt:ident, $($field:ident = $vector:literal),* $(,)?) {
+        $($idt.$field.set_handler_fn(entry::stub($vector));)*
+    }
+
+    let mut idt = InterruptDescriptorTable::new();
+    unsafe {
+        set_stubs!(
+            idt,
+            divide_error = 0,
+            debug = 1,
+            non_maskable_interrupt = 2,
+            breakpoint = 3,
+            overflow = 4,
+            bound_range_exceeded = 5,
+            invalid_opcode = 6,
+            device_not_available = 7,
+            invalid_tss = 10,
+            segment_not_present = 11,
+            stack_segment_fault = 12,
+            general_protection_fault = 13,
+            page_fault = 14,
+            x87_floating_point = 16,
+            alignment_check = 17,
+            machine_check = 18,
+            simd_floating_point = 19,
+            virtualization = 20,
+            security_exception = 30,
+        );
+        idt.double_fault
+            .set_handler_fn(entry::stub(8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+
+        // Everything else is routed through the `irq` registry
+        for vector in 32..256 {
+            idt[vector].set_handler_fn(entry::stub(vector));
+        }
+    }
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+    idt
+}
+
+/// Initializes the IDT.
+///
+/// # Safety
+/// Must only be called once.
+pub unsafe fn init_idt() {
+    IDT.call_once(build_idt).load();
+}
+
+/// Initializes an IDT for an application processor. It is allocated on the
+/// heap and never freed.
+///
+/// # Safety
+/// Must only be called once per application processor.
+pub unsafe fn init_ap_idt() {
+    Box::leak(Box::new(build_idt())).load();
+}
+
+/// Spurious interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// TESTS
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_breakpoint_exception() {
+    serial_print!("test_breakpoint_exception... ");
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+}
@@ -1,5 +1,8 @@
+use super::cpuid::{self, Feature};
 use crate::allocator::{HEAP_SIZE, HEAP_START};
 use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
@@ -8,6 +11,159 @@ use x86_64::{
     PhysAddr, VirtAddr,
 };
 
+/// Start of the virtual address window used for MMIO mappings.
+pub const MMIO_START: u64 = 0x_4444_8888_0000;
+/// Frames below this address are only handed out by `allocate_low_frame`.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<Mutex<BootInfoFrameAllocator>> = Once::new();
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Stores the page table and frame allocator so that they can be used
+/// after the heap has been set up.
+///
+/// # Safety
+/// Must only be called once, with the mapper and frame allocator used to
+/// set up the heap.
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    MAPPER.call_once(|| Mutex::new(mapper));
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(frame_allocator));
+}
+
+/// Returns the virtual address the given physical address is mapped to by
+/// the bootloader.
+///
+/// # Panics
+/// Panics if the memory module is not initialized.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory not initialized")
+        + addr.as_u64()
+}
+
+/// Whether `addr` is mapped in the current page table.
+///
+/// Walks the page table without taking any locks, so it can be used while
+/// handling faults. Returns `false` if the memory module is not initialized.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::{registers::control::Cr3, structures::paging::page_table::PageTableIndex};
+
+    let offset = match PHYSICAL_MEMORY_OFFSET.get() {
+        Some(offset) => *offset,
+        None => return false,
+    };
+    let indices: [PageTableIndex; 4] = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_addr = Cr3::read().0.start_address();
+    for (level, index) in indices.iter().enumerate() {
+        let table = unsafe { &*(offset + table_addr.as_u64()).as_ptr::<PageTable>() };
+        let entry = &table[*index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // 1 GiB and 2 MiB pages end the walk early
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table_addr = entry.addr();
+    }
+    true
+}
+
+/// Runs `f` with the kernel page table and frame allocator locked.
+///
+/// # Panics
+/// Panics if the memory module is not initialized.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+    let mut frame_allocator = FRAME_ALLOCATOR
+        .get()
+        .expect("memory not initialized")
+        .lock();
+    f(&mut mapper, &mut frame_allocator)
+}
+
+/// Maps `size` bytes of device memory starting at `phys` as uncached and
+/// returns the virtual address of `phys`.
+///
+/// # Safety
+/// `phys` must point to device memory that is not used by anything else.
+///
+/// # Panics
+/// Panics if the mapping fails.
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end_frame = PhysFrame::<Size4KiB>::containing_address(phys + size - 1_u64);
+    let frame_count = end_frame - start_frame + 1;
+    let virt_start = VirtAddr::new(NEXT_MMIO.fetch_add(frame_count * 4096, Ordering::Relaxed));
+
+    with_mapper(|mapper, frame_allocator| {
+        let mut flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+        // The NX bit is reserved without NX support
+        if cpuid::has(Feature::Nx) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+            let page = Page::containing_address(virt_start + i * 4096);
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("Could not map MMIO region")
+                .flush();
+        }
+    });
+
+    virt_start + (phys - start_frame.start_address())
+}
+
+/// Identity maps the given frame, doing nothing if it is already identity mapped.
+///
+/// # Safety
+/// See `Mapper::identity_map`.
+///
+/// # Errors
+/// Returns an error if the mapping fails.
+pub unsafe fn identity_map(
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper, frame_allocator| {
+        match mapper.identity_map(frame, flags, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(()),
+            Err(e) => Err(e),
+        }
+    })
+}
+
+/// Allocates a frame below `LOW_MEMORY_END`, for use by real mode code.
+///
+/// # Panics
+/// Panics if the memory module is not initialized.
+pub fn allocate_low_frame() -> Option<PhysFrame> {
+    with_mapper(|_, frame_allocator| frame_allocator.allocate_low_frame())
+}
+
 /// This is where the heap is actually initialized.
 /// Calculates the page range, allocates the frames, and then maps the pages to the allocated frames. Lastly, calls the static `ALLOCATOR`'s `init` function.
 ///
@@ -58,6 +214,7 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
+    next_low: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -71,11 +228,28 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            next_low: 0,
         }
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
+    /// Allocates a frame below `LOW_MEMORY_END`, skipping the first frame.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self
+            .all_usable_frames()
+            .filter(|f| (4096..LOW_MEMORY_END).contains(&f.start_address().as_u64()))
+            .nth(self.next_low);
+        self.next_low += 1;
+        frame
+    }
+
+    /// Returns an iterator over the usable frames above `LOW_MEMORY_END`.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.all_usable_frames()
+            .filter(|f| f.start_address().as_u64() >= LOW_MEMORY_END)
+    }
+
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn all_usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
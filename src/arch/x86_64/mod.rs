@@ -1,30 +1,74 @@
 //! `x86_64` specific code.
+pub mod acpi;
+pub mod backtrace;
+pub mod context;
+pub mod cpuid;
 pub mod device;
+pub mod fpu;
+#[cfg(feature = "gdb")]
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod smp;
 pub mod task;
+pub mod tsc;
+pub mod watchpoint;
 
+use crate::time::clocksource::{self, ClockSource};
+use alloc::vec::Vec;
 use bootloader::{boot_info::MemoryRegions, BootInfo};
 
-/// Initializes the GDT, interrupts, devices and lastly the heap.
+/// Initializes the GDT, interrupts, the heap, devices and lastly starts the
+/// other CPUs.
 ///
 /// # Safety
 /// Must only be called once.
 #[allow(clippy::inline_always)]
 #[inline(always)] // Inline because it will be only used once anyways
 pub unsafe fn init(boot_info: &'static mut BootInfo) {
+    percpu::init();
     crate::logger::init();
+    cpuid::init();
     gdt::init();
     interrupts::init_idt();
-    device::init(boot_info.framebuffer.as_mut());
+    // Interrupt handlers are registered on the heap
     init_heap(
         boot_info.physical_memory_offset.into_option(),
         &boot_info.memory_regions,
     );
+    fpu::init();
+    device::init(boot_info.framebuffer.as_mut());
+    acpi::init(boot_info.rsdp_addr.into_option());
+    init_clocksource();
+    device::rtc::init();
+    smp::init();
+    watchpoint::init();
+    #[cfg(feature = "gdb")]
+    gdb::init();
     log::info!("Initialized all peripherals!");
 }
 
+/// Finds the HPET, calibrates the TSC and picks the better one of them as
+/// clock source.
+///
+/// # Safety
+/// Must only be called once, after ACPI has been initialized.
+unsafe fn init_clocksource() {
+    device::hpet::init();
+    tsc::init();
+
+    let mut candidates: Vec<&'static dyn ClockSource> = Vec::new();
+    if let Some(hpet) = device::hpet::hpet() {
+        candidates.push(hpet);
+    }
+    if let Some(tsc) = tsc::tsc() {
+        candidates.push(tsc);
+    }
+    clocksource::select(&candidates);
+}
+
 /// Initializes the heap.
 /// This gets the mapper and a `BootInfoFrameAllocator` from the given `BootInfo`, then calls `setup_heap` from the `memory` module.
 ///
@@ -44,6 +88,7 @@ pub unsafe fn init_heap(
     let mut frame_allocator = memory::BootInfoFrameAllocator::init(memory_regions);
 
     memory::setup_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init(phys_mem_offset, mapper, frame_allocator);
 }
 
 /// Make an entry point. This macro checks the signature of the provided
@@ -0,0 +1,46 @@
+//! Logical CPU IDs through the GS base.
+//!
+//! The GS base of every CPU points to a small area starting with its logical
+//! ID, so `cpu_id` is a single `gs` relative load.
+use alloc::boxed::Box;
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+/// The area the GS base points to.
+#[repr(C)]
+struct Area {
+    id: usize,
+}
+
+static BSP_AREA: Area = Area { id: 0 };
+
+/// Returns the logical ID of the current CPU.
+#[inline]
+pub fn cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) id,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+    id
+}
+
+/// Points the GS base of the BSP to its area.
+///
+/// # Safety
+/// Must be called on the BSP before anything uses per-CPU variables.
+pub unsafe fn init() {
+    GsBase::write(VirtAddr::from_ptr(&BSP_AREA));
+}
+
+/// Points the GS base of an AP to a new area with `id`. The area is
+/// allocated on the heap and never freed.
+///
+/// # Safety
+/// Must be called once per AP, before anything uses per-CPU variables.
+pub unsafe fn init_ap(id: usize) {
+    let area: &'static Area = Box::leak(Box::new(Area { id }));
+    GsBase::write(VirtAddr::from_ptr(area));
+}
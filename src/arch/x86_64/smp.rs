@@ -0,0 +1,428 @@
+//! Symmetric multiprocessing support.
+//!
+//! Application processors (APs) are discovered through the MADT and started
+//! with the INIT-SIPI-SIPI sequence. They begin executing a real mode
+//! trampoline that switches straight to long mode using the page table of
+//! the bootstrap processor (BSP), and then jump to `ap_main`.
+use super::{
+    acpi,
+    cpuid::{self, Feature},
+    device::{
+        apic::{self, Delivery},
+        pit,
+    },
+    fpu, gdt, interrupts, memory, percpu,
+};
+use crate::percpu::MAX_CPUS;
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Once;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+/// Size of the kernel stack of each application processor.
+const AP_STACK_SIZE: usize = 4096 * 16;
+
+global_asm!(
+    r#"
+.section .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_trampoline_gdt
+.global ap_trampoline_gdt_ptr
+.global ap_trampoline_far_ptr
+.global ap_trampoline_long_mode
+.global ap_trampoline_cr3
+.global ap_trampoline_stack
+.global ap_trampoline_entry
+.global ap_trampoline_arg
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    // Enable PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    // Use the page table of the BSP
+    mov ap_trampoline_cr3 - ap_trampoline_start, %eax
+    mov %eax, %cr3
+
+    // Enable long mode and no-execute in EFER
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    lgdtl ap_trampoline_gdt_ptr - ap_trampoline_start
+
+    // Enable paging, write protection and protected mode at once
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16) | 1), %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_trampoline_far_ptr - ap_trampoline_start)
+
+.code64
+ap_trampoline_long_mode:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov ap_trampoline_stack(%rip), %rsp
+    mov ap_trampoline_arg(%rip), %rdi
+    mov ap_trampoline_entry(%rip), %rax
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+.balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+ap_trampoline_gdt_ptr:
+    .word ap_trampoline_gdt_ptr - ap_trampoline_gdt - 1
+    .long 0
+ap_trampoline_far_ptr:
+    .long 0
+    .word 0x08
+
+.balign 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_arg:
+    .quad 0
+ap_trampoline_end:
+
+.code64
+.text
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdt_ptr: u8;
+    static ap_trampoline_far_ptr: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+/// The trampoline copied to low memory, where APs start executing.
+struct Trampoline {
+    frame: PhysFrame,
+    virt: VirtAddr,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to `frame` and patches in the absolute addresses.
+    ///
+    /// # Safety
+    /// `frame` must be an unused frame below 1 MiB.
+    unsafe fn new(frame: PhysFrame) -> Self {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= 4096, "AP trampoline doesn't fit in a frame");
+
+        // Paging gets enabled while executing from the physical address
+        memory::identity_map(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            .expect("Could not identity map AP trampoline");
+
+        let virt = memory::phys_to_virt(frame.start_address());
+        ptr::copy_nonoverlapping(start, virt.as_mut_ptr::<u8>(), len);
+
+        let trampoline = Trampoline { frame, virt };
+        let phys = frame.start_address().as_u64();
+        // Real mode code can only use 32 bit addresses, the trampoline is in
+        // low memory so these never truncate
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            // `lgdt` takes a 16 bit limit followed by the base
+            let gdt = phys + trampoline.offset(&ap_trampoline_gdt);
+            trampoline.write(&ap_trampoline_gdt_ptr, 2, gdt as u32);
+            let long_mode = phys + trampoline.offset(&ap_trampoline_long_mode);
+            trampoline.write(&ap_trampoline_far_ptr, 0, long_mode as u32);
+        }
+
+        let cr3 = Cr3::read().0.start_address().as_u64();
+        assert!(cr3 <= u64::from(u32::MAX), "page table is above 4 GiB");
+        trampoline.write(&ap_trampoline_cr3, 0, cr3);
+
+        trampoline
+    }
+
+    /// Returns the offset of `symbol` from the start of the trampoline.
+    fn offset(&self, symbol: &u8) -> u64 {
+        unsafe { symbol as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64 }
+    }
+
+    /// Writes `value` at `extra` bytes after `symbol` in the copied trampoline.
+    unsafe fn write<T>(&self, symbol: &u8, extra: u64, value: T) {
+        let addr = self.virt + self.offset(symbol) + extra;
+        ptr::write_unaligned(addr.as_mut_ptr::<T>(), value);
+    }
+
+    /// Returns the SIPI vector, which is the page number of the trampoline.
+    #[allow(clippy::cast_possible_truncation)]
+    fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+}
+
+/// Per-CPU data area.
+#[derive(Debug)]
+pub struct Cpu {
+    /// Logical ID of the CPU. The BSP always has ID `0`.
+    pub id: usize,
+    /// ID of the local APIC of this CPU.
+    pub apic_id: u8,
+    online: AtomicBool,
+}
+
+impl Cpu {
+    fn new(id: usize, apic_id: u8) -> Self {
+        Cpu {
+            id,
+            apic_id,
+            online: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns whether this CPU is started and running kernel code.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Returns whether this CPU is the bootstrap processor.
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+}
+
+static CPUS: Once<Vec<Cpu>> = Once::new();
+static WORK: Once<fn() -> !> = Once::new();
+
+/// Returns an iterator over all online CPUs.
+pub fn cpus() -> impl Iterator<Item = &'static Cpu> {
+    CPUS.get()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter(|cpu| cpu.is_online())
+}
+
+/// Returns the number of online CPUs.
+pub fn cpu_count() -> usize {
+    cpus().count()
+}
+
+/// Returns the data area of the CPU this is called on.
+///
+/// # Panics
+/// Panics if SMP is not initialized.
+pub fn current_cpu() -> &'static Cpu {
+    let cpus = CPUS.get().expect("SMP not initialized");
+    &cpus[percpu::cpu_id()]
+}
+
+/// Registers the BSP as the only CPU.
+fn run_on_bsp_only() {
+    CPUS.call_once(|| vec![Cpu::new(0, 0)])[0]
+        .online
+        .store(true, Ordering::Release);
+}
+
+/// Discovers the CPUs in the system and starts all APs. The APs are parked
+/// in `idle` afterwards.
+///
+/// # Safety
+/// Must only be called once, on the BSP, after the heap and ACPI have been
+/// initialized.
+pub unsafe fn init() {
+    let madt = match acpi::madt() {
+        Some(madt) if cpuid::has(Feature::Apic) => madt,
+        Some(_) => {
+            log::warn!("CPU has no local APIC, running on the BSP only");
+            return run_on_bsp_only();
+        }
+        None => {
+            log::warn!("no MADT found, running on the BSP only");
+            return run_on_bsp_only();
+        }
+    };
+
+    apic::init(madt.local_apic_address);
+    let lapic = apic::local_apic().expect("local APIC not initialized");
+    let bsp_apic_id = lapic.id();
+
+    let mut cpus = vec![Cpu::new(0, bsp_apic_id)];
+    cpus.extend(
+        madt.processors
+            .iter()
+            .filter(|p| p.enabled && p.apic_id != bsp_apic_id)
+            .enumerate()
+            .map(|(i, p)| Cpu::new(i + 1, p.apic_id))
+            .take(MAX_CPUS - 1),
+    );
+    if cpus.len() == MAX_CPUS {
+        log::warn!("only using the first {} CPUs", MAX_CPUS);
+    }
+    let cpus = CPUS.call_once(|| cpus);
+    cpus[0].online.store(true, Ordering::Release);
+
+    if cpus.len() == 1 {
+        log::info!("found no application processors");
+        return;
+    }
+
+    let trampoline = if let Some(frame) = memory::allocate_low_frame() {
+        Trampoline::new(frame)
+    } else {
+        log::warn!("no low memory frame for the AP trampoline, running on the BSP only");
+        return;
+    };
+
+    // The BSP has the PIT to end time slices, the APs use their own timer
+    apic::init_timer();
+
+    for cpu in &cpus[1..] {
+        if !start_ap(&trampoline, cpu) {
+            // It may still start late and use what the trampoline points to,
+            // so that can't be patched for another one
+            log::warn!("not starting the remaining APs");
+            break;
+        }
+    }
+
+    log::info!("{} of {} CPUs online", cpu_count(), cpus.len());
+}
+
+/// Starts `cpu` and waits until it is online. Returns `false` if it didn't
+/// come online in time.
+unsafe fn start_ap(trampoline: &Trampoline, cpu: &'static Cpu) -> bool {
+    let lapic = apic::local_apic().expect("local APIC not initialized");
+
+    let stack: &'static mut [u8] = Box::leak(vec![0; AP_STACK_SIZE].into_boxed_slice());
+    let stack_end = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+
+    trampoline.write(&ap_trampoline_stack, 0, stack_end);
+    trampoline.write(&ap_trampoline_entry, 0, ap_main as usize as u64);
+    trampoline.write(&ap_trampoline_arg, 0, cpu as *const Cpu as u64);
+
+    lapic.send_ipi(cpu.apic_id, Delivery::Init, 0);
+    pit::busy_wait(10_000);
+
+    // The second SIPI is only needed if the first one got lost
+    for timeout in &[1_000, 100_000] {
+        lapic.send_ipi(cpu.apic_id, Delivery::Startup, trampoline.vector());
+        if wait_online(cpu, *timeout) {
+            return true;
+        }
+    }
+
+    log::warn!("CPU {} (APIC ID {}) did not start", cpu.id, cpu.apic_id);
+    false
+}
+
+/// Waits at most `micros` microseconds for `cpu` to come online.
+fn wait_online(cpu: &Cpu, micros: u64) -> bool {
+    const STEP: u64 = 100;
+
+    for _ in 0..micros / STEP {
+        if cpu.is_online() {
+            return true;
+        }
+        pit::busy_wait(STEP);
+    }
+    cpu.is_online()
+}
+
+/// Rust entry point of application processors, called by the trampoline.
+extern "C" fn ap_main(cpu: &'static Cpu) -> ! {
+    unsafe {
+        percpu::init_ap(cpu.id);
+        gdt::init_ap();
+        interrupts::init_ap_idt();
+        fpu::init_ap();
+    }
+    apic::local_apic()
+        .expect("local APIC not initialized")
+        .enable();
+    apic::start_timer();
+    super::watchpoint::load();
+
+    cpu.online.store(true, Ordering::Release);
+    log::info!("CPU {} (APIC ID {}) online", cpu.id, cpu.apic_id);
+
+    idle()
+}
+
+/// Wakes the CPU with logical ID `id` from `hlt` with an IPI. Does nothing
+/// for the current CPU, which is obviously awake.
+pub fn wake(id: usize) {
+    if id == percpu::cpu_id() {
+        return;
+    }
+    let cpu = CPUS.get().and_then(|cpus| cpus.get(id));
+    if let (Some(cpu), Some(lapic)) = (cpu, apic::local_apic()) {
+        lapic.send_ipi(cpu.apic_id, Delivery::Fixed, apic::WAKEUP_VECTOR);
+    }
+}
+
+/// Parks the current CPU until work is started with `start_work`.
+pub fn idle() -> ! {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        interrupts::disable();
+        if let Some(work) = WORK.get() {
+            interrupts::enable();
+            work();
+        }
+        // If the wakeup IPI arrived inbetween, `hlt` returns immediately
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Makes all parked APs run `work`. Only the first call has an effect.
+pub fn start_work(work: fn() -> !) {
+    WORK.call_once(|| work);
+    if let Some(lapic) = apic::local_apic() {
+        lapic.broadcast_ipi(apic::WAKEUP_VECTOR);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_bsp_online() {
+    serial_print!("test_bsp_online... ");
+    assert!(cpus().any(Cpu::is_bsp));
+    assert!(current_cpu().is_online());
+    serial_println!("[ok]");
+}
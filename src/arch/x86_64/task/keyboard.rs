@@ -1,36 +1,32 @@
-use core::{
-    pin::Pin,
-    task::{Context, Poll},
+use crate::task::{
+    deferred::{self, EventQueue, EventStream},
+    executor::SpawnError,
 };
-use crossbeam_queue::ArrayQueue;
-use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
+use futures_util::stream::StreamExt;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use spin::Once;
 
 const SC_CAP: usize = 100;
 /// Holds scancodes added by `add_scancode`.
-static SCANCODES: Once<ArrayQueue<u8>> = Once::new();
-static SCANCODES_WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODES: EventQueue<u8> = EventQueue::new("scancode", SC_CAP);
 
-static DECODED_KEYS: Once<ArrayQueue<DecodedKey>> = Once::new();
-static DECODED_KEYS_WAKER: AtomicWaker = AtomicWaker::new();
+static DECODED_KEYS: EventQueue<DecodedKey> = EventQueue::new("decoded key", SC_CAP);
 
-fn clear_array_queue<T>(queue: &ArrayQueue<T>) {
-    while queue.pop().is_some() {}
+/// Starts decoding scancodes asynchronously.
+///
+/// # Errors
+/// Returns an error if the executor has not been initialized.
+pub fn init() -> Result<(), SpawnError> {
+    deferred::register(&SCANCODES, handle_scancodes)
 }
 
-/// Handles scancodes asynchronously.
-pub async fn handle_scancodes() {
-    DECODED_KEYS.call_once(|| ArrayQueue::new(SC_CAP));
-
-    let mut scancodes = ScancodeStream::new();
+async fn handle_scancodes(mut scancodes: EventStream<u8>) {
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
 
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
-                add_decoded_key(key);
+                // Overflows are accounted for by the queue
+                let _ = DECODED_KEYS.push(key);
             }
         }
     }
@@ -40,93 +36,21 @@ pub async fn handle_scancodes() {
 ///
 /// Must not block or allocate.
 pub fn add_scancode(scancode: u8) {
-    use log::warn;
-
-    if let Some(queue) = SCANCODES.get() {
-        if queue.push(scancode).is_err() {
-            warn!("scancode queue full, clearing queue to avoid dropping keyboard input");
-            clear_array_queue(queue);
-        } else {
-            SCANCODES_WAKER.wake();
-        }
-    } else {
-        warn!("scancode queue uninitialized");
-    }
+    // Overflows are accounted for by the queue
+    let _ = SCANCODES.push(scancode);
 }
 
-/// Called by the scancode handler function.
+/// Spawns the consumer of decoded keys.
 ///
-/// Must not block or allocate.
-fn add_decoded_key(key: DecodedKey) {
-    use log::warn;
-    if let Some(d) = DECODED_KEYS.get() {
-        if d.push(key).is_err() {
-            warn!("decoded key queue full");
-        } else {
-            DECODED_KEYS_WAKER.wake();
-        }
-    } else {
-        warn!("decoded key queue uninitialized");
-    }
-}
-
-/// Polls scancodes from `SCANCODE_QUEUE`.
-pub struct ScancodeStream {
-    _private: (),
-}
-
-impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODES.call_once(|| ArrayQueue::new(SC_CAP));
-        ScancodeStream { _private: () }
-    }
-}
-
-impl Stream for ScancodeStream {
-    type Item = u8;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let queue = SCANCODES.get().expect("not initialized");
-
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        SCANCODES_WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                SCANCODES_WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
-    }
-}
-
-/// Polls `DecodedKey`s from `DECODED_KEYS`.
-pub struct DecodedKeyStream;
-
-impl Stream for DecodedKeyStream {
-    type Item = DecodedKey;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let queue = if let Some(q) = DECODED_KEYS.get() {
-            q
-        } else {
-            return Poll::Pending;
-        };
-
-        if let Some(key) = queue.pop() {
-            return Poll::Ready(Some(key));
-        }
-
-        DECODED_KEYS_WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(key) => {
-                DECODED_KEYS_WAKER.take();
-                Poll::Ready(Some(key))
-            }
-            None => Poll::Pending,
-        }
-    }
+/// # Errors
+/// Returns an error if the executor has not been initialized.
+///
+/// # Panics
+/// Panics if decoded keys already have a consumer.
+pub fn on_decoded_keys<F, Fut>(consumer: F) -> Result<(), SpawnError>
+where
+    F: FnOnce(EventStream<DecodedKey>) -> Fut,
+    Fut: crate::task::Future + 'static,
+{
+    deferred::register(&DECODED_KEYS, consumer)
 }
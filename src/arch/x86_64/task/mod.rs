@@ -1,3 +1,3 @@
 pub mod keyboard;
 
-pub use keyboard::{add_scancode, handle_scancodes, DecodedKeyStream};
+pub use keyboard::{add_scancode, on_decoded_keys};
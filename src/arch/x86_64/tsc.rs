@@ -0,0 +1,107 @@
+//! Time Stamp Counter.
+//!
+//! The TSC frequency isn't architecturally exposed, so it is measured
+//! against the HPET, or the PIT if there is no HPET.
+use super::{
+    cpuid::{self, Feature},
+    device::{hpet, pit},
+};
+use crate::time::clocksource::ClockSource;
+use core::arch::x86_64::_rdtsc;
+use spin::Once;
+
+/// How long calibration runs for.
+const CALIBRATION_MICROS: u64 = 10_000;
+/// The lowest result of this many calibration runs is used.
+const CALIBRATION_RUNS: usize = 3;
+
+/// Reads the TSC.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC runs at a constant rate in all power states, which is
+/// required to use it as a clock source.
+pub fn is_invariant() -> bool {
+    cpuid::has(Feature::InvariantTsc)
+}
+
+/// The TSC, with its measured frequency.
+pub struct Tsc {
+    frequency: u64,
+    invariant: bool,
+}
+
+impl Tsc {
+    /// Returns the measured frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        if self.invariant {
+            300
+        } else {
+            // Might change speed or stop in deep sleep states
+            80
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn now_ns(&self) -> u64 {
+        (u128::from(read()) * 1_000_000_000 / u128::from(self.frequency)) as u64
+    }
+}
+
+static TSC: Once<Tsc> = Once::new();
+
+/// Returns the TSC ticks passed during one calibration wait.
+fn measure() -> u64 {
+    crate::arch::woint(|| {
+        if let Some(hpet) = hpet::hpet() {
+            let ticks = hpet.frequency() * CALIBRATION_MICROS / 1_000_000;
+            let start = read();
+            hpet.wait_ticks(ticks);
+            read() - start
+        } else {
+            let start = read();
+            pit::busy_wait(CALIBRATION_MICROS);
+            read() - start
+        }
+    })
+}
+
+/// Measures the TSC frequency.
+///
+/// Should be called after `hpet::init`, the PIT is less accurate.
+pub fn init() {
+    // Interrupts and SMIs only ever make a run longer
+    let ticks = (0..CALIBRATION_RUNS).map(|_| measure()).min().unwrap_or(0);
+    let frequency = ticks * 1_000_000 / CALIBRATION_MICROS;
+    if frequency == 0 {
+        log::warn!("TSC calibration failed");
+        return;
+    }
+
+    let invariant = is_invariant();
+    log::info!(
+        "TSC runs at {} kHz, invariant: {}",
+        frequency / 1000,
+        invariant
+    );
+    TSC.call_once(|| Tsc {
+        frequency,
+        invariant,
+    });
+}
+
+/// Returns the calibrated TSC, if calibration succeeded.
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.get()
+}
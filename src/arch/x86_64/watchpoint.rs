@@ -0,0 +1,260 @@
+//! Hardware watchpoints using the debug registers.
+//!
+//! Watchpoints are kept in a table shared by all CPUs. Changing it reloads
+//! the debug registers of the current CPU and sends an IPI to the others so
+//! they do the same.
+use super::{
+    device::apic,
+    interrupts::{
+        irq::{self, IrqResult},
+        TrapFrame,
+    },
+    woint,
+};
+use crate::{backtrace::Backtrace, symbols::Symbolized};
+use core::fmt;
+use spin::{Mutex, Once};
+
+/// Number of watchpoints the hardware supports.
+pub const COUNT: usize = 4;
+
+const DR6_HITS: u64 = 0b1111;
+/// Debug register access (BD), single step (BS) and task switch (BT).
+const DR6_OTHER: u64 = 0b111 << 13;
+const RESUME_FLAG: u64 = 1 << 16;
+
+/// What a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Executing the instruction at the address. The length must be 1.
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+/// A watched address range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: usize,
+    /// 1, 2, 4 or 8 bytes, `address` must be aligned to it.
+    pub len: usize,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {} byte(s) at {}",
+            self.kind,
+            self.len,
+            Symbolized(self.address)
+        )
+    }
+}
+
+/// Error returned by `set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    NoFreeSlot,
+    UnsupportedLength,
+    Misaligned,
+}
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; COUNT]> = Mutex::new([None; COUNT]);
+static RELOAD_VECTOR: Once<u8> = Once::new();
+
+macro debug_register {
+    (read $n:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mov {}, dr", $n), out(reg) value, options(nomem, nostack)) };
+        value
+    }},
+    (write $n:literal, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe { asm!(concat!("mov dr", $n, ", {}"), in(reg) value, options(nomem, nostack)) };
+    }},
+}
+
+/// Sets up the IPI used to reload the debug registers on other CPUs.
+///
+/// Should be called after the local APIC has been initialized.
+pub fn init() {
+    let vector = match irq::request_vector() {
+        Some(vector) => vector,
+        None => {
+            log::warn!("no vector left for watchpoint IPIs");
+            return;
+        }
+    };
+    irq::register(vector, |_| {
+        load();
+        IrqResult::Handled
+    });
+    RELOAD_VECTOR.call_once(|| vector);
+}
+
+/// Starts watching `watchpoint`, returning its slot.
+///
+/// # Errors
+/// Returns an error if all slots are in use or the range can't be watched.
+pub fn set(watchpoint: Watchpoint) -> Result<usize, WatchError> {
+    let len_ok = match watchpoint.kind {
+        Kind::Execute => watchpoint.len == 1,
+        Kind::Write | Kind::ReadWrite => matches!(watchpoint.len, 1 | 2 | 4 | 8),
+    };
+    if !len_ok {
+        return Err(WatchError::UnsupportedLength);
+    }
+    if watchpoint.address % watchpoint.len != 0 {
+        return Err(WatchError::Misaligned);
+    }
+
+    let slot = woint(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let slot = watchpoints.iter().position(Option::is_none)?;
+        watchpoints[slot] = Some(watchpoint);
+        Some(slot)
+    })
+    .ok_or(WatchError::NoFreeSlot)?;
+    sync();
+    Ok(slot)
+}
+
+/// Stops watching the watchpoint in `slot`. Returns `false` if the slot was
+/// empty.
+pub fn clear(slot: usize) -> bool {
+    let cleared = woint(|| {
+        WATCHPOINTS
+            .lock()
+            .get_mut(slot)
+            .and_then(Option::take)
+            .is_some()
+    });
+    if cleared {
+        sync();
+    }
+    cleared
+}
+
+/// Returns the watchpoints in all slots.
+pub fn watchpoints() -> [Option<Watchpoint>; COUNT] {
+    woint(|| *WATCHPOINTS.lock())
+}
+
+fn sync() {
+    load();
+    if let (Some(lapic), Some(vector)) = (apic::local_apic(), RELOAD_VECTOR.get()) {
+        lapic.broadcast_ipi(*vector);
+    }
+}
+
+/// Loads the watchpoint table into the debug registers of the current CPU.
+pub fn load() {
+    let watchpoints = watchpoints();
+    let mut dr7 = 0;
+    for (slot, watchpoint) in watchpoints.iter().enumerate() {
+        let watchpoint = match watchpoint {
+            Some(watchpoint) => watchpoint,
+            None => continue,
+        };
+        let condition = match watchpoint.kind {
+            Kind::Execute => 0b00,
+            Kind::Write => 0b01,
+            Kind::ReadWrite => 0b11,
+        };
+        let len = match watchpoint.len {
+            2 => 0b01,
+            4 => 0b11,
+            8 => 0b10,
+            _ => 0b00,
+        };
+        // Local enable, condition and length
+        dr7 |= (1 << (slot * 2)) | ((condition | (len << 2)) << (16 + slot * 4));
+
+        let address = watchpoint.address as u64;
+        match slot {
+            0 => debug_register!(write 0, address),
+            1 => debug_register!(write 1, address),
+            2 => debug_register!(write 2, address),
+            _ => debug_register!(write 3, address),
+        }
+    }
+    debug_register!(write 7, dr7);
+}
+
+/// Reports watchpoint hits in a debug exception. Returns `false` if the
+/// exception wasn't caused by a watchpoint, or also by something else, like
+/// a single step, which is then left for the other debug handlers.
+#[allow(clippy::cast_possible_truncation)]
+pub fn handle(frame: &mut TrapFrame) -> bool {
+    let dr6 = debug_register!(read 6);
+    if dr6 & DR6_HITS == 0 {
+        return false;
+    }
+
+    let watchpoints = watchpoints();
+    for (slot, watchpoint) in watchpoints.iter().enumerate() {
+        if dr6 & (1 << slot) == 0 {
+            continue;
+        }
+        let watchpoint = match watchpoint {
+            Some(watchpoint) => watchpoint,
+            None => continue,
+        };
+        log::warn!(
+            "watchpoint {} ({}) hit at {}\n{}",
+            slot,
+            watchpoint,
+            Symbolized(frame.rip as usize),
+            Backtrace::from_frame(frame.rip as usize, frame.rbp as usize)
+        );
+        // Execute breakpoints fault before the instruction runs, don't hit
+        // them again when returning
+        if watchpoint.kind == Kind::Execute {
+            frame.rflags |= RESUME_FLAG;
+        }
+    }
+    // The CPU never clears the status bits itself
+    debug_register!(write 6, dr6 & !DR6_HITS);
+    dr6 & DR6_OTHER == 0
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_watchpoint_slots() {
+    static WATCHED: [u64; COUNT + 1] = [0; COUNT + 1];
+
+    serial_print!("test_watchpoint_slots... ");
+    let watchpoint = |i: usize| Watchpoint {
+        address: &WATCHED[i] as *const u64 as usize,
+        len: 8,
+        kind: Kind::Write,
+    };
+    let mut slots = [0; COUNT];
+    for (i, slot) in slots.iter_mut().enumerate() {
+        *slot = set(watchpoint(i)).unwrap();
+    }
+    assert_eq!(set(watchpoint(COUNT)), Err(WatchError::NoFreeSlot));
+    assert!(watchpoints().iter().all(Option::is_some));
+
+    for &slot in &slots {
+        assert!(clear(slot));
+        assert!(!clear(slot));
+    }
+    assert!(watchpoints().iter().all(Option::is_none));
+
+    let misaligned = Watchpoint {
+        address: watchpoint(0).address + 1,
+        ..watchpoint(0)
+    };
+    assert_eq!(set(misaligned), Err(WatchError::Misaligned));
+    let execute = Watchpoint {
+        kind: Kind::Execute,
+        ..watchpoint(0)
+    };
+    assert_eq!(set(execute), Err(WatchError::UnsupportedLength));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,130 @@
+//! Frame pointer based stack unwinding.
+//!
+//! On both supported architectures the frame pointer points to a frame
+//! record holding the caller's frame pointer followed by the return address.
+//! Addresses are symbolized with the embedded symbol table.
+//! Every record is validated before it is read, so a corrupt stack ends the
+//! backtrace instead of faulting.
+use crate::{arch::backtrace as arch, symbols::Symbolized};
+use core::{fmt, mem};
+
+/// Backtraces are cut off after this many frames.
+const MAX_DEPTH: usize = 64;
+/// Frames larger than this are considered corrupt.
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// A frame of the call chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    /// Frame pointer of the frame.
+    pub fp: usize,
+    /// Address execution continues at when the frame returns.
+    pub return_address: usize,
+}
+
+/// Iterator over the frames of a call chain, innermost first.
+pub struct Frames {
+    fp: usize,
+    depth: usize,
+}
+
+impl Frames {
+    fn is_valid(&self, fp: usize) -> bool {
+        fp != 0
+            && fp % mem::align_of::<usize>() == 0
+            && arch::is_readable(fp, 2 * mem::size_of::<usize>())
+    }
+}
+
+impl Iterator for Frames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.depth >= MAX_DEPTH || !self.is_valid(self.fp) {
+            return None;
+        }
+
+        let record = self.fp as *const usize;
+        let (next_fp, return_address) = unsafe { (record.read(), record.add(1).read()) };
+        if return_address == 0 {
+            return None;
+        }
+        let frame = Frame {
+            fp: self.fp,
+            return_address,
+        };
+
+        // The stack grows down, so callers' frames must be above this one
+        self.fp = if next_fp > self.fp && next_fp - self.fp <= MAX_FRAME_SIZE {
+            next_fp
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(frame)
+    }
+}
+
+/// A call chain, printed as a list of addresses.
+pub struct Backtrace {
+    pc: Option<usize>,
+    fp: usize,
+}
+
+impl Backtrace {
+    /// Captures the call chain of the caller.
+    #[allow(clippy::inline_always)]
+    #[inline(always)] // Inline so the caller's frame pointer is read
+    pub fn capture() -> Self {
+        Backtrace {
+            pc: None,
+            fp: arch::frame_pointer(),
+        }
+    }
+
+    /// Creates a backtrace of interrupted code from its program counter and
+    /// frame pointer.
+    pub fn from_frame(pc: usize, fp: usize) -> Self {
+        Backtrace { pc: Some(pc), fp }
+    }
+
+    /// Returns the frames of the call chain.
+    pub fn frames(&self) -> Frames {
+        Frames {
+            fp: self.fp,
+            depth: 0,
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        let mut index = 0;
+        if let Some(pc) = self.pc {
+            write!(f, "\n  #{:<2} {}", index, Symbolized(pc))?;
+            index += 1;
+        }
+        for frame in self.frames() {
+            write!(f, "\n  #{:<2} {}", index, Symbolized(frame.return_address))?;
+            index += 1;
+        }
+        if index == 0 {
+            write!(f, " <unavailable>")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_capture_backtrace() {
+    serial_print!("test_capture_backtrace... ");
+    let backtrace = Backtrace::capture();
+    assert!(backtrace.frames().next().is_some());
+    // Corrupt frame pointers end the backtrace instead of faulting
+    assert_eq!(Backtrace::from_frame(0, 0x8).frames().count(), 0);
+    serial_println!("[ok]");
+}
@@ -3,6 +3,7 @@
 #![cfg_attr(test, no_main)]
 #![feature(
     asm,
+    global_asm,
     decl_macro,
     custom_test_frameworks,
     abi_x86_interrupt,
@@ -21,7 +22,12 @@ extern crate alloc;
 
 pub mod allocator;
 pub mod arch;
+pub mod backtrace;
 pub mod logger;
 pub mod memory;
+pub mod percpu;
+pub mod symbols;
 pub mod task;
 pub mod test;
+pub mod thread;
+pub mod time;
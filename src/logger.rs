@@ -1,4 +1,5 @@
 //! Implementation of `Log` trait.
+use core::fmt;
 use log::{LevelFilter, Log, Metadata, Record};
 
 pub static LOGGER: Logger = Logger;
@@ -29,6 +30,19 @@ pub fn init() {
 
 pub struct Logger;
 
+/// Formats as the current date and time if the wall clock is set, as the
+/// time since boot otherwise.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match crate::time::wall::now() {
+            Some(now) => write!(f, "{}", now),
+            None => write!(f, "{}", crate::time::Instant::now()),
+        }
+    }
+}
+
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= log::max_level()
@@ -40,6 +54,7 @@ impl Log for Logger {
             let file_path = record.file().unwrap_or("<unknown file>");
             #[cfg(debug_assertions)]
             let line_number = record.line().unwrap_or(0);
+            let timestamp = Timestamp;
             let log_level = record.level();
             let message = record.args();
 
@@ -47,27 +62,29 @@ impl Log for Logger {
             {
                 #[cfg(debug_assertions)]
                 crate::println!(
-                    "[{}:{}] [{}] {}",
+                    "[{}] [{}:{}] [{}] {}",
+                    timestamp,
                     file_path,
                     line_number,
                     log_level,
                     message,
                 );
                 #[cfg(not(debug_assertions))]
-                crate::println!("[{}] {}", log_level, message);
+                crate::println!("[{}] [{}] {}", timestamp, log_level, message);
             }
             #[cfg(feature = "log_serial")]
             {
                 #[cfg(debug_assertions)]
                 crate::serial_println!(
-                    "[{}:{}] [{}] {}",
+                    "[{}] [{}:{}] [{}] {}",
+                    timestamp,
                     file_path,
                     line_number,
                     log_level,
                     message,
                 );
                 #[cfg(not(debug_assertions))]
-                crate::serial_println!("[{}] {}", log_level, message);
+                crate::serial_println!("[{}] [{}] {}", timestamp, log_level, message);
             }
         }
     }
@@ -12,8 +12,8 @@ extern crate alloc;
 
 #[cfg(target_arch = "x86_64")]
 use hakkero::{
-    arch::task::{handle_scancodes, DecodedKeyStream},
-    task::{self, Executor, Task},
+    arch::task::keyboard,
+    task::{executor::start_workers, Executor, Task},
 };
 
 // NOTE: All supported architectures must have entry_point implemented!
@@ -28,7 +28,9 @@ fn kernel_main() -> ! {
     heap_info();
 
     log::info!("Welcome to Hakkero OS!\n");
-    Executor::new().spawn(Task::new(start_handlers())).run()
+    let mut executor = Executor::new().spawn(Task::new(start_handlers()));
+    start_workers();
+    executor.run()
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -50,7 +52,8 @@ fn heap_info() {
 
     info!("Heap start: {}", HEAP_START);
     info!("Heap size : {}", HEAP_SIZE);
-    info!("Heap usage: {}", ALLOCATOR.lock().used_heap());
+    let used = hakkero::arch::woint(|| ALLOCATOR.lock().used_heap());
+    info!("Heap usage: {}", used);
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -58,13 +61,11 @@ async fn start_handlers() {
     use pc_keyboard::DecodedKey;
 
     log::info!("starting services");
-    task::spawn(Task::new(handle_scancodes())).unwrap();
+    keyboard::init().unwrap();
     log::info!("handle keyboard scancodes started");
-    task::spawn(Task::new(async {
+    keyboard::on_decoded_keys(|mut queue| async move {
         use futures_util::stream::StreamExt;
 
-        let mut queue = DecodedKeyStream;
-
         while let Some(key) = queue.next().await {
             hakkero::print!(
                 "{}",
@@ -74,6 +75,6 @@ async fn start_handlers() {
                 }
             );
         }
-    }))
+    })
     .unwrap();
 }
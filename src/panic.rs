@@ -6,7 +6,7 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    log::error!("{}", info);
+    log::error!("{}\n{}", info, hakkero::backtrace::Backtrace::capture());
     hakkero::arch::hang_cpu();
 }
 
@@ -0,0 +1,86 @@
+//! Per-CPU variables.
+//!
+//! Every CPU finds its logical ID through a register that is set up when it
+//! starts (the GS base on `x86_64`, `TPIDR_EL1` on `AArch64`), see
+//! `arch::percpu`. Per-CPU variables are arrays indexed by that ID.
+//!
+//! ```ignore
+//! per_cpu! {
+//!     static COUNTER: AtomicU64 = AtomicU64::new(0);
+//! }
+//!
+//! COUNTER.get().fetch_add(1, Ordering::Relaxed);
+//! ```
+use crate::arch::{percpu::cpu_id, smp};
+
+/// Maximum number of CPUs, the others are left parked.
+pub const MAX_CPUS: usize = 64;
+
+/// A variable with a separate value for every CPU. Declared with `per_cpu!`.
+///
+/// Other CPUs can still read a value through `for_cpu` and `iter`, so it has
+/// to be `Sync`, but usually only its own CPU touches it.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// Returns the value of the current CPU.
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    /// Returns the value of the CPU with logical ID `id`.
+    pub fn for_cpu(&self, id: usize) -> Option<&T> {
+        self.values.get(id)
+    }
+
+    /// Returns an iterator over the values of all online CPUs.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        smp::cpus().map(move |cpu| &self.values[cpu.id])
+    }
+}
+
+/// Declares per-CPU variables. The initializer must be a constant expression
+/// and is evaluated once for every CPU.
+pub macro per_cpu($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) {
+    $(
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const INIT: $ty = $init;
+            $crate::percpu::PerCpu::new([INIT; $crate::percpu::MAX_CPUS])
+        };
+    )*
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_per_cpu() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    per_cpu! {
+        static VALUE: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    serial_print!("test_per_cpu... ");
+    VALUE.get().store(42, Ordering::Relaxed);
+    let id = cpu_id();
+    assert_eq!(VALUE.for_cpu(id).unwrap().load(Ordering::Relaxed), 42);
+    assert_eq!(
+        VALUE
+            .iter()
+            .map(|v| v.load(Ordering::Relaxed))
+            .sum::<usize>(),
+        42
+    );
+    serial_println!("[ok]");
+}
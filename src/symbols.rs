@@ -0,0 +1,140 @@
+//! Kernel symbol table, for turning addresses into `function+offset`.
+//!
+//! The kernel reserves a zeroed `.ksymtab` section that the `boot` crate
+//! fills in after linking. Until then, lookups find nothing.
+//!
+//! Layout, all integers little endian:
+//! - header: magic `KSYM`, entry count (`u32`), string table size (`u32`)
+//! - entries, sorted by address: address (`u64`), size (`u32`), name offset
+//!   (`u32`), name length (`u32`)
+//! - string table: the demangled names, without terminators
+use core::{convert::TryInto, fmt, str};
+
+/// Size reserved for the symbol table. The `boot` crate drops symbols that
+/// don't fit.
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 20;
+
+#[used]
+#[link_section = ".ksymtab"]
+static mut SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// A function in the kernel image.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+}
+
+impl Symbol {
+    /// Whether `address` is inside this function.
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.address && address - self.address < self.size.max(1)
+    }
+}
+
+fn table() -> &'static [u8] {
+    let ptr = unsafe { SYMBOL_TABLE.as_ptr() };
+    // The table is written after linking, so the compiler must not assume it
+    // still holds zeroes. Passing it to `asm!` makes it opaque.
+    unsafe { asm!("/* {} */", in(reg) ptr, options(nostack, preserves_flags)) };
+    unsafe { core::slice::from_raw_parts(ptr, SYMBOL_TABLE_SIZE) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn read_u64(bytes: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// Parsed view of the embedded table.
+struct Table {
+    entries: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Table {
+    fn get() -> Option<Self> {
+        let table = table();
+        if &table[..4] != MAGIC {
+            return None;
+        }
+        let count = read_u32(table, 4);
+        let strings_size = read_u32(table, 8);
+        let entries_end = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+        let strings_end = entries_end.checked_add(strings_size)?;
+        Some(Table {
+            entries: table.get(HEADER_SIZE..entries_end)?,
+            strings: table.get(entries_end..strings_end)?,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn address(&self, index: usize) -> usize {
+        read_u64(self.entries, index * ENTRY_SIZE)
+    }
+
+    fn symbol(&self, index: usize) -> Symbol {
+        let entry = &self.entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+        let name_offset = read_u32(entry, 12);
+        let name_len = read_u32(entry, 16);
+        let name = self
+            .strings
+            .get(name_offset..name_offset + name_len)
+            .and_then(|name| str::from_utf8(name).ok())
+            .unwrap_or("<invalid>");
+        Symbol {
+            name,
+            address: read_u64(entry, 0),
+            size: read_u32(entry, 8),
+        }
+    }
+}
+
+/// Returns the function containing `address`, if there is a symbol table
+/// and a function there.
+pub fn lookup(address: usize) -> Option<Symbol> {
+    let table = Table::get()?;
+
+    // Index of the last symbol starting at or before `address`
+    let (mut low, mut high) = (0, table.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if table.address(mid) <= address {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let symbol = table.symbol(low.checked_sub(1)?);
+    if symbol.contains(address) {
+        Some(symbol)
+    } else {
+        None
+    }
+}
+
+/// Formats an address as `0x...` followed by `function+offset` if it is
+/// known.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some(symbol) = lookup(self.0) {
+            write!(f, " {}+{:#x}", symbol.name, self.0 - symbol.address)?;
+        }
+        Ok(())
+    }
+}
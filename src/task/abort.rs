@@ -0,0 +1,166 @@
+//! Cancelling spawned tasks.
+//!
+//! An aborted task is dropped the next time the executor would poll it,
+//! which it is woken for. Its `JoinHandle` then resolves to
+//! `JoinError::Aborted`.
+use super::{executor, executor::SpawnError, join, JoinHandle, Task};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Abort flag of a task, shared with its `AbortHandle`s.
+pub(super) struct AbortState {
+    aborted: AtomicBool,
+    /// Waker of the task, registered every time it is polled.
+    pub(super) waker: AtomicWaker,
+}
+
+impl AbortState {
+    pub(super) fn new() -> Self {
+        AbortState {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    pub(super) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// Handle to abort a spawned task. Can be cloned and used from interrupt
+/// handlers.
+#[derive(Clone)]
+pub struct AbortHandle {
+    state: Arc<AbortState>,
+}
+
+impl AbortHandle {
+    pub(super) fn new(state: Arc<AbortState>) -> Self {
+        AbortHandle { state }
+    }
+
+    /// Aborts the task, does nothing if it already completed.
+    pub fn abort(&self) {
+        self.state.abort();
+    }
+
+    /// Whether `abort` has been called.
+    pub fn is_aborted(&self) -> bool {
+        self.state.is_aborted()
+    }
+}
+
+struct GroupInner {
+    aborted: AtomicBool,
+    members: Mutex<Vec<Weak<AbortState>>>,
+}
+
+/// A set of tasks that are aborted together, like all tasks started by a
+/// shell command.
+///
+/// Tasks aren't added to the group of the task spawning them, the group has
+/// to be passed along for that.
+#[derive(Clone)]
+pub struct TaskGroup {
+    inner: Arc<GroupInner>,
+}
+
+impl TaskGroup {
+    /// Creates an empty group.
+    pub fn new() -> Self {
+        TaskGroup {
+            inner: Arc::new(GroupInner {
+                aborted: AtomicBool::new(false),
+                members: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Spawns `future` as a member of the group, see `executor::spawn`.
+    ///
+    /// # Errors
+    /// Returns an error if no executor has been initialized.
+    pub fn spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + Sync + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future, super::Priority::default());
+        self.spawn_task(task)?;
+        Ok(handle)
+    }
+
+    /// Spawns `task` as a member of the group, see `executor::spawn_task`.
+    ///
+    /// # Errors
+    /// Returns an error if no executor has been initialized.
+    pub fn spawn_task(&self, task: Task) -> Result<(), SpawnError> {
+        self.add(&task);
+        executor::spawn_task(task)
+    }
+
+    fn add(&self, task: &Task) {
+        let mut members = self.inner.members.lock();
+        // Forget tasks that are gone
+        members.retain(|member| member.strong_count() > 0);
+        members.push(Arc::downgrade(&task.abort));
+        if self.is_aborted() {
+            task.abort.abort();
+        }
+    }
+
+    /// Aborts all tasks in the group, including ones spawned into it later.
+    pub fn abort_all(&self) {
+        self.inner.aborted.store(true, Ordering::Release);
+        let members = core::mem::take(&mut *self.inner.members.lock());
+        for member in members.iter().filter_map(Weak::upgrade) {
+            member.abort();
+        }
+    }
+
+    /// Whether `abort_all` has been called.
+    pub fn is_aborted(&self) -> bool {
+        self.inner.aborted.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_task_group_abort() {
+    use super::{join::JoinError, simple_executor::SimpleExecutor, Priority};
+    use futures_util::future::{pending, FutureExt};
+
+    serial_print!("test_task_group_abort... ");
+    let group = TaskGroup::new();
+    let (first, first_handle) = join::joinable(pending::<()>(), Priority::default());
+    let (second, second_handle) = join::joinable(pending::<()>(), Priority::default());
+    group.add(&first);
+    group.abort_all();
+    group.add(&second);
+    // Would poll forever if the tasks weren't dropped
+    SimpleExecutor::new().spawn(first).spawn(second).run();
+    assert!(matches!(
+        first_handle.now_or_never(),
+        Some(Err(JoinError::Aborted))
+    ));
+    assert!(matches!(
+        second_handle.now_or_never(),
+        Some(Err(JoinError::Aborted))
+    ));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,165 @@
+//! Deferred interrupt work.
+//!
+//! Interrupt handlers only push events into an `EventQueue`, the actual work
+//! happens in an async consumer registered with `register`, which the
+//! executor runs before other tasks.
+use super::{executor::SpawnError, Priority, Task};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Once;
+
+/// Lock-free queue of events from one interrupt source.
+///
+/// Events pushed while the queue is full are dropped and counted, the
+/// consumer reports them the next time it receives an event.
+pub struct EventQueue<T> {
+    name: &'static str,
+    capacity: usize,
+    queue: Once<ArrayQueue<T>>,
+    /// Set once a consumer is being registered.
+    claimed: AtomicBool,
+    waker: AtomicWaker,
+    dropped: AtomicU64,
+    reported: AtomicU64,
+}
+
+impl<T> EventQueue<T> {
+    /// Creates a queue that holds up to `capacity` events. The buffer is
+    /// allocated when the consumer is registered.
+    pub const fn new(name: &'static str, capacity: usize) -> Self {
+        EventQueue {
+            name,
+            capacity,
+            queue: Once::new(),
+            claimed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            dropped: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the name of the queue.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Queues an event and wakes the consumer.
+    ///
+    /// Never blocks or allocates, so it is safe to call from interrupt
+    /// handlers.
+    ///
+    /// # Errors
+    /// Returns the event if the queue is full or has no consumer yet.
+    pub fn push(&self, event: T) -> Result<(), T> {
+        let queue = match self.queue.get() {
+            Some(queue) => queue,
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Err(event);
+            }
+        };
+        let result = queue.push(event);
+        if result.is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        // Even if full, so the consumer catches up
+        self.waker.wake();
+        result
+    }
+
+    /// Returns how many events have been dropped in total.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of events waiting to be consumed.
+    pub fn len(&self) -> usize {
+        self.queue.get().map_or(0, ArrayQueue::len)
+    }
+
+    /// Whether no events are waiting to be consumed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Logs events dropped since the last report.
+    fn report_dropped(&self) {
+        let dropped = self.dropped();
+        let reported = self.reported.swap(dropped, Ordering::Relaxed);
+        if dropped > reported {
+            log::warn!(
+                "{} queue overflowed, dropped {} events",
+                self.name,
+                dropped - reported
+            );
+        }
+    }
+}
+
+/// Stream of the events of an `EventQueue`, handed to its consumer.
+pub struct EventStream<T: 'static> {
+    source: &'static EventQueue<T>,
+}
+
+impl<T> EventStream<T> {
+    fn pop(&self) -> Option<T> {
+        // The buffer is allocated right after the consumer was spawned
+        self.source.queue.get()?.pop()
+    }
+}
+
+impl<T> Stream for EventStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(event) = self.pop() {
+            self.source.report_dropped();
+            return Poll::Ready(Some(event));
+        }
+
+        self.source.waker.register(cx.waker());
+        match self.pop() {
+            Some(event) => {
+                self.source.waker.take();
+                self.source.report_dropped();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Spawns the consumer of `source`. `consumer` gets the stream of events
+/// and returns the future processing them.
+///
+/// # Errors
+/// Returns an error if the executor has not been initialized.
+///
+/// # Panics
+/// Panics if `source` already has a consumer.
+pub fn register<T, F, Fut>(source: &'static EventQueue<T>, consumer: F) -> Result<(), SpawnError>
+where
+    T: 'static,
+    F: FnOnce(EventStream<T>) -> Fut,
+    Fut: super::Future + 'static,
+{
+    assert!(
+        !source.claimed.swap(true, Ordering::AcqRel),
+        "{} queue already has a consumer",
+        source.name
+    );
+    let task = Task::with_priority(consumer(EventStream { source }), Priority::BottomHalf)
+        .named(source.name);
+    if let Err(error) = super::spawn_task(task) {
+        // Registering can be tried again
+        source.claimed.store(false, Ordering::Release);
+        return Err(error);
+    }
+    source.queue.call_once(|| ArrayQueue::new(source.capacity));
+    Ok(())
+}
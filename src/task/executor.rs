@@ -1,22 +1,68 @@
-//! Simple FIFO `Task` executor.
-use super::{Task, TaskId};
+//! Priority based, work stealing `Task` executor.
+//!
+//! Every CPU running an executor has a worker with its own run queue. Ready
+//! tasks are queued by their `Priority` and the highest class is run first.
+//! Every time a lower class is passed over it ages, once it has been passed
+//! over `MAX_STARVATION` times in a row one of its tasks runs next.
+//!
+//! Every executor pins its thread to its CPU, the worker and the per-CPU
+//! state of the executor belong to that CPU.
+//!
+//! Spawned tasks go to a shared injection queue that all workers take from.
+//! A worker that runs out of tasks steals half of the ready tasks of another
+//! one, and halts if no worker has any. Wakers queue the task on the worker
+//! that polled it last and wake that CPU if it is halted. Tasks are only
+//! stolen while ready, so the worker polling one next hands out its own
+//! waker, and wakers of the previous one only cause a spurious wakeup.
+//!
+//! `LocalTask`s never leave the executor they were spawned on, they can only
+//! be spawned by tasks it polls. They have a run queue of their own, its next
+//! task competes with the shared one by priority class and ties take turns.
+//!
+//! Spawned tasks are added to the statistics table of `super::stats`, every
+//! poll and wakeup is recorded there.
+use super::{
+    join,
+    stats::{self, Stats},
+    JoinHandle, LocalTask, Priority, Task, TaskId,
+};
+use crate::{
+    arch::{percpu::cpu_id, woint},
+    percpu::per_cpu,
+    thread::{self, ThreadId},
+};
 use alloc::{
+    borrow::Cow,
+    boxed::Box,
     collections::{BTreeMap, VecDeque},
-    sync::Arc,
+    sync::{Arc, Weak},
     task::Wake,
+    vec::Vec,
+};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
 };
-use core::task::{Context, Poll, Waker};
 use crossbeam_queue::SegQueue;
-use spin::Once;
+use spin::{Mutex, Once};
 
 struct TaskWaker {
     task_id: TaskId,
-    wake_queue: Arc<SegQueue<TaskId>>,
+    /// Weak so wakers kept after the task finished don't keep it listed.
+    stats: Weak<Stats>,
+    worker: Arc<Worker>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.wake_queue.push(self.task_id);
+        if let Some(stats) = self.stats.upgrade() {
+            stats.woken();
+        }
+        self.worker.wake_queue.push(self.task_id);
+        self.worker.unpark();
     }
 }
 
@@ -30,24 +76,62 @@ impl Wake for TaskWaker {
     }
 }
 
-/// Error returned by the `spawn_task` function.
+/// Error returned by the `spawn_task` and `spawn_local_task` functions.
 #[derive(Debug)]
 pub enum SpawnError {
     ExecutorNotInitialized,
+    /// Local tasks can only be spawned by a task an executor is polling.
+    NotInTask,
 }
 
-/// Clone of `waiting_for_task_queue` in the Executor.
-static WFTQ: Once<Arc<SegQueue<Task>>> = Once::new();
+/// No task is being polled.
+const NO_TASK: u64 = u64::MAX;
+/// How many times in a row a class with ready tasks can be passed over.
+pub const MAX_STARVATION: usize = 8;
+/// How many spawned tasks a worker takes from the injection queue at once,
+/// the rest is left for others.
+const INJECT_BATCH: usize = 16;
+
+/// Tasks spawned with `spawn_task`, created with the first executor.
+static INJECTOR: Once<SegQueue<Task>> = Once::new();
 
-/// Queues the task to be run in the next poll.
+per_cpu! {
+    /// Worker of the executor of each CPU.
+    static WORKERS: Once<Arc<Worker>> = Once::new();
+    /// ID of the task being polled on each CPU, or `NO_TASK`.
+    static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+    /// Local spawns of the executor polling a task on each CPU, or null.
+    static POLLING: AtomicPtr<LocalSpawns> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// Local tasks spawned by the tasks an executor polls, not yet taken by it.
+struct LocalSpawns {
+    /// Thread running the executor.
+    thread: ThreadId,
+    tasks: UnsafeCell<Vec<LocalTask>>,
+}
+
+impl LocalSpawns {
+    /// Runs `f` on the spawned tasks. Only called on the thread of the
+    /// executor, which is pinned, interrupts are disabled so its handlers
+    /// can't get in between.
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<LocalTask>) -> R) -> R {
+        woint(|| f(unsafe { &mut *self.tasks.get() }))
+    }
+}
+
+/// Queues the task on the injection queue, the next worker looking for
+/// tasks takes it from there.
 ///
 /// # Errors
-/// Returns an error if the executor has not been initialized.
+/// Returns an error if no executor has been initialized.
 pub fn spawn_task(task: Task) -> Result<(), SpawnError> {
     use log::warn;
 
-    if let Some(s) = WFTQ.get() {
-        s.push(task);
+    if let Some(injector) = INJECTOR.get() {
+        stats::register(&task.stats);
+        injector.push(task);
+        wake_idle_worker();
     } else {
         warn!("executor not initialized, can't spawn task");
         return Err(SpawnError::ExecutorNotInitialized);
@@ -56,109 +140,570 @@ pub fn spawn_task(task: Task) -> Result<(), SpawnError> {
     Ok(())
 }
 
-/// Simple FIFO task executor. Supports wakers.
+/// Spawns `future` as a task with the default priority, see `spawn_task`.
+///
+/// # Errors
+/// Returns an error if no executor has been initialized.
+pub fn spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + Sync + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with_priority(future, Priority::default())
+}
+
+/// Spawns `future` as a task with the given priority, see `spawn_task`.
+///
+/// # Errors
+/// Returns an error if no executor has been initialized.
+pub fn spawn_with_priority<F>(
+    future: F,
+    priority: Priority,
+) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + Sync + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().priority(priority).spawn(future)
+}
+
+/// Queues the task for the executor polling the calling task, which is the
+/// only one that ever polls it.
+///
+/// # Errors
+/// Returns an error if not called from a task an executor is polling.
+pub fn spawn_local_task(task: LocalTask) -> Result<(), SpawnError> {
+    use log::warn;
+
+    let thread = thread::current().id();
+    // Checked with interrupts disabled, so the thread can't be switched
+    // away from inbetween
+    let spawns = woint(|| {
+        let spawns = POLLING.get().load(Ordering::Relaxed);
+        // Another thread may have preempted the executor mid-poll
+        unsafe { spawns.as_ref() }.filter(|spawns| spawns.thread == thread)
+    });
+    match spawns {
+        Some(spawns) => {
+            stats::register(&task.stats);
+            spawns.with(|spawns| spawns.push(task));
+            Ok(())
+        }
+        None => {
+            warn!("not polled by an executor, can't spawn local task");
+            Err(SpawnError::NotInTask)
+        }
+    }
+}
+
+/// Spawns `future`, which doesn't have to be `Send`, as a local task with
+/// the default priority, see `spawn_local_task`.
+///
+/// # Errors
+/// Returns an error if not called from a task an executor is polling.
+pub fn spawn_local<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + 'static,
+{
+    Builder::new().spawn_local(future)
+}
+
+/// Spawns joinable tasks with a name or priority.
+///
+/// ```ignore
+/// let handle = Builder::new()
+///     .name("flush")
+///     .priority(Priority::Background)
+///     .spawn(flush())?;
+/// ```
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<Cow<'static, str>>,
+    priority: Priority,
+}
+
+impl Builder {
+    /// Creates a builder for an unnamed task with the default priority.
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// Names the task, the name shows up in its statistics.
+    pub fn name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the priority of the task.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawns `future` as a task, see `spawn_task`.
+    ///
+    /// # Errors
+    /// Returns an error if no executor has been initialized.
+    pub fn spawn<F>(self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + Sync + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = join::joinable(future, self.priority);
+        spawn_task(match self.name {
+            Some(name) => task.named(name),
+            None => task,
+        })?;
+        Ok(handle)
+    }
+
+    /// Spawns `future` as a local task, see `spawn_local_task`.
+    ///
+    /// # Errors
+    /// Returns an error if not called from a task an executor is polling.
+    pub fn spawn_local<F>(self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = join::joinable_local(future, self.priority);
+        spawn_local_task(match self.name {
+            Some(name) => task.named(name),
+            None => task,
+        })?;
+        Ok(handle)
+    }
+}
+
+/// Returns the ID of the task being polled on the current CPU.
+pub fn current_task() -> Option<TaskId> {
+    match CURRENT_TASK.get().load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+/// Runs an executor on every other online CPU. They take spawned tasks and
+/// steal from the executors already running.
+pub fn start_workers() {
+    crate::arch::smp::start_work(|| Executor::new().run());
+}
+
+/// Wakes a halted worker, if there is one, to look for tasks.
+fn wake_idle_worker() {
+    let idle = WORKERS
+        .iter()
+        .filter_map(Once::get)
+        .find(|worker| worker.sleeping.load(Ordering::SeqCst));
+    if let Some(worker) = idle {
+        worker.unpark();
+    }
+}
+
+/// Wakes the BSP if it is halted, so it programs the timer for a deadline
+/// that was just added.
+pub(super) fn timer_added() {
+    if let Some(worker) = WORKERS.for_cpu(0).and_then(Once::get) {
+        worker.unpark();
+    }
+}
+
+/// Ready tasks of each priority class.
+struct RunQueue<T> {
+    queues: [VecDeque<T>; Priority::COUNT],
+    /// How many times each class has been passed over.
+    starvation: [usize; Priority::COUNT],
+}
+
+impl<T> RunQueue<T> {
+    fn new() -> Self {
+        RunQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            starvation: [0; Priority::COUNT],
+        }
+    }
+
+    fn push(&mut self, priority: Priority, task: T) {
+        self.queues[priority as usize].push_back(task);
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Class of the task `pop` would take.
+    fn next_class(&self) -> Option<usize> {
+        let ready = |c: &usize| !self.queues[*c].is_empty();
+        (0..Priority::COUNT)
+            .filter(ready)
+            .find(|&c| self.starvation[c] >= MAX_STARVATION)
+            .or_else(|| (0..Priority::COUNT).find(ready))
+    }
+
+    /// Takes the next task to run, aging the classes it passes over.
+    fn pop(&mut self) -> Option<T> {
+        let class = self.next_class()?;
+
+        let classes = self.queues.iter().zip(&mut self.starvation);
+        for (other, (queue, starvation)) in classes.enumerate() {
+            if other == class {
+                *starvation = 0;
+            } else if other > class && !queue.is_empty() {
+                *starvation += 1;
+            }
+        }
+        self.queues[class].pop_front()
+    }
+
+    /// Moves half of the tasks of every class, rounded up, to `other`.
+    fn steal_into(&mut self, other: &mut RunQueue<T>) {
+        for (from, to) in self.queues.iter_mut().zip(&mut other.queues) {
+            let len = from.len();
+            // The owner pops from the front
+            to.extend(from.drain(len / 2..));
+        }
+    }
+}
+
+/// The part of an executor that other CPUs use.
+struct Worker {
+    /// Logical ID of the CPU running the executor.
+    cpu: usize,
+    run_queue: Mutex<RunQueue<Task>>,
+    wake_queue: SegQueue<TaskId>,
+    /// Set while the CPU is halted waiting for tasks.
+    sleeping: AtomicBool,
+}
+
+impl Worker {
+    /// Wakes the CPU if it is halted.
+    fn unpark(&self) {
+        if self.sleeping.load(Ordering::SeqCst) {
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::x86_64::smp::wake(self.cpu);
+        }
+    }
+}
+
+/// Priority based, work stealing task executor. Supports wakers.
 pub struct Executor {
-    task_queue: VecDeque<Task>,
-    waiting_for_task_queue: Arc<SegQueue<Task>>,
+    worker: Arc<Worker>,
     waiting_tasks: BTreeMap<TaskId, Task>,
-    wake_queue: Arc<SegQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    local_ready: RunQueue<LocalTask>,
+    local_waiting: BTreeMap<TaskId, LocalTask>,
+    /// Boxed so `POLLING` can point to it while the executor moves.
+    local_spawns: Box<LocalSpawns>,
+    /// Whether a local task won the last tie with the shared run queue.
+    local_turn: bool,
 }
 
 impl Executor {
-    /// Creates a new `Executor`.
+    /// Creates a new `Executor` for the current CPU and pins the running
+    /// thread to it.
     pub fn new() -> Self {
-        let waiting_for_task_queue = Arc::new(SegQueue::new());
-        WFTQ.call_once(|| waiting_for_task_queue.clone());
+        crate::thread::pin_current();
+        INJECTOR.call_once(SegQueue::new);
+        let worker = WORKERS.get().call_once(|| {
+            Arc::new(Worker {
+                cpu: cpu_id(),
+                run_queue: Mutex::new(RunQueue::new()),
+                wake_queue: SegQueue::new(),
+                sleeping: AtomicBool::new(false),
+            })
+        });
         Executor {
-            task_queue: VecDeque::new(),
-            waiting_for_task_queue,
+            worker: worker.clone(),
             waiting_tasks: BTreeMap::new(),
-            wake_queue: Arc::new(SegQueue::new()),
-            waker_cache: BTreeMap::new(),
+            local_ready: RunQueue::new(),
+            local_waiting: BTreeMap::new(),
+            local_spawns: Box::new(LocalSpawns {
+                thread: thread::current().id(),
+                tasks: UnsafeCell::new(Vec::new()),
+            }),
+            local_turn: false,
         }
     }
 
-    /// Spawns the given `Task` by queuing it.
-    pub fn spawn(mut self, task: Task) -> Self {
-        self.task_queue.push_back(task);
+    /// Spawns the given `Task` by queuing it on this executor.
+    pub fn spawn(self, task: Task) -> Self {
+        stats::register(&task.stats);
+        self.worker.run_queue.lock().push(task.priority, task);
+        self
+    }
+
+    /// Spawns the given `LocalTask` on this executor.
+    pub fn spawn_local(mut self, task: LocalTask) -> Self {
+        stats::register(&task.stats);
+        self.local_ready.push(task.priority, task);
         self
     }
 
     /// Starts logic loop; waking tasks, running ready tasks and sleeping.
     pub fn run(&mut self) -> ! {
         loop {
-            self.wake_tasks();
+            // Catches up if the timer interrupt couldn't take the lock
+            super::timer::advance();
             self.run_ready_tasks();
             #[cfg(target_arch = "x86_64")]
-            self.sleep_if_idle(); // Getting here means that there are no tasks left in `task_queue`
+            self.sleep_if_idle(); // Getting here means that there are no tasks left to run or steal
         }
     }
 
+    /// Whether tasks were woken or spawned, local ones are ready, or other
+    /// workers have tasks to steal.
+    fn has_work(&self) -> bool {
+        !self.worker.wake_queue.is_empty()
+            || INJECTOR.get().map_or(false, |i| !i.is_empty())
+            || !self.local_ready.is_empty()
+            || self.local_spawns.with(|spawns| !spawns.is_empty())
+            || self.can_steal()
+    }
+
+    /// Whether another worker has ready tasks. A run queue that is locked
+    /// right now may have some.
+    fn can_steal(&self) -> bool {
+        WORKERS
+            .iter()
+            .filter_map(Once::get)
+            .filter(|victim| victim.cpu != self.worker.cpu)
+            .any(|victim| victim.run_queue.try_lock().map_or(true, |q| !q.is_empty()))
+    }
+
+    /// Halts until an interrupt arrives, or lets other threads run if there
+    /// are any. Unless time keeping depends on the periodic tick, the timer
+    /// is only programmed to fire at the next timer deadline, or not at all
+    /// if there are no timers, while halted. Only the BSP has a timer, other
+    /// CPUs are woken when they get tasks.
     #[cfg(target_arch = "x86_64")]
     fn sleep_if_idle(&self) {
+        use crate::{
+            arch::x86_64::device::pit,
+            time::{clocksource, Instant},
+        };
         use x86_64::instructions::interrupts;
 
         // Return early, no need to disable interrupts
-        if !self.wake_queue.is_empty() {
+        if self.has_work() {
+            return;
+        }
+        if crate::thread::has_ready() {
+            crate::thread::yield_now();
             return;
         }
 
         interrupts::disable();
+        // Either wakers see the flag, or this sees what they queued
+        self.worker.sleeping.store(true, Ordering::SeqCst);
         // If an interrupt happened inbetween, interrupts will be enabled
-        if self.wake_queue.is_empty() {
-            interrupts::enable_and_hlt();
-        } else {
+        if self.has_work() {
+            self.worker.sleeping.store(false, Ordering::SeqCst);
             interrupts::enable();
+            return;
+        }
+
+        let keeps_time = self.worker.cpu == 0;
+        if keeps_time && !clocksource::needs_tick() {
+            match super::timer::next_deadline() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        // Expired while running tasks, `run` will wake it
+                        self.worker.sleeping.store(false, Ordering::SeqCst);
+                        interrupts::enable();
+                        return;
+                    }
+                    pit::set_oneshot(deadline - now);
+                }
+                None => pit::stop(),
+            }
+        }
+        interrupts::enable_and_hlt();
+        self.worker.sleeping.store(false, Ordering::SeqCst);
+        if keeps_time {
+            // Other threads need the tick to be preempted
+            pit::resume_periodic();
         }
     }
 
-    fn create_waker(&self, task_id: TaskId) -> Waker {
+    fn create_waker(&self, task_id: TaskId, stats: &Arc<Stats>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
-            wake_queue: self.wake_queue.clone(),
+            stats: Arc::downgrade(stats),
+            worker: self.worker.clone(),
         }))
     }
 
-    fn run_ready_tasks(&mut self) {
-        while let Some(task) = self.waiting_for_task_queue.pop() {
-            self.task_queue.push_back(task);
-        }
-        while let Some(mut task) = self.task_queue.pop_front() {
-            let task_id = task.id;
-            // Create a new `Waker` if it isn't already in the cache.
-            #[allow(clippy::map_entry)]
-            if !self.waker_cache.contains_key(&task_id) {
-                self.waker_cache.insert(task_id, self.create_waker(task_id));
+    /// Moves woken and spawned tasks to the run queues. If that leaves more
+    /// than one shared task, a halted worker is woken to steal some.
+    fn queue_ready_tasks(&mut self) {
+        let mut run_queue = self.worker.run_queue.lock();
+        let mut added = false;
+        while let Some(task_id) = self.worker.wake_queue.pop() {
+            if let Some(task) = self.waiting_tasks.remove(&task_id) {
+                run_queue.push(task.priority, task);
+                added = true;
+            } else if let Some(task) = self.local_waiting.remove(&task_id) {
+                self.local_ready.push(task.priority, task);
             }
+        }
+        if let Some(injector) = INJECTOR.get() {
+            for task in core::iter::from_fn(|| injector.pop()).take(INJECT_BATCH) {
+                run_queue.push(task.priority, task);
+                added = true;
+            }
+        }
+        let local_ready = &mut self.local_ready;
+        self.local_spawns.with(|spawns| {
+            for task in spawns.drain(..) {
+                local_ready.push(task.priority, task);
+            }
+        });
+        let len = run_queue.len();
+        drop(run_queue);
+        if added && len > 1 {
+            wake_idle_worker();
+        }
+    }
 
-            let waker = self
-                .waker_cache
-                .get(&task_id)
-                .expect("There should be a waker with this key. I hope.");
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // Task is done, remove cached waker
-                    self.waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {
-                    // Task isn't done, back to waiting list
-                    if self.waiting_tasks.insert(task_id, task).is_some() {
-                        panic!("Task with same ID already in waiting_tasks! Literally how");
-                    }
+    /// Steals tasks from another worker, trying the CPUs after this one
+    /// first, and returns one of them.
+    fn steal(&self) -> Option<Task> {
+        let cpu = self.worker.cpu;
+        let workers = || WORKERS.iter().filter_map(Once::get);
+        let victims = workers()
+            .filter(|victim| victim.cpu > cpu)
+            .chain(workers().filter(|victim| victim.cpu < cpu));
+
+        let mut stolen = RunQueue::new();
+        for victim in victims {
+            // Never holds two run queues at once, so this can't deadlock with
+            // a victim stealing from this worker
+            victim.run_queue.lock().steal_into(&mut stolen);
+            if let Some(task) = stolen.pop() {
+                let mut run_queue = self.worker.run_queue.lock();
+                for queue in &mut stolen.queues {
+                    queue
+                        .drain(..)
+                        .for_each(|task| run_queue.push(task.priority, task));
                 }
+                return Some(task);
             }
         }
+        None
     }
 
-    fn wake_tasks(&mut self) {
-        while let Some(task_id) = self.wake_queue.pop() {
-            if let Some(task) = self.waiting_tasks.remove(&task_id) {
-                self.task_queue.push_back(task);
+    /// Returns the waker of this executor for `task`, creating it if the
+    /// task was last polled elsewhere.
+    fn waker_for(&self, task: &mut Task) -> Waker {
+        let cpu = self.worker.cpu;
+        match &task.waker {
+            Some((owner, waker)) if *owner == cpu => waker.clone(),
+            _ => {
+                let waker = self.create_waker(task.id, &task.stats);
+                task.waker = Some((cpu, waker.clone()));
+                waker
             }
         }
     }
+
+    /// Whether the next task comes from the local run queue rather than
+    /// the shared one.
+    fn local_goes_next(&mut self) -> bool {
+        let local = match self.local_ready.next_class() {
+            Some(class) => class,
+            None => return false,
+        };
+        match self.worker.run_queue.lock().next_class() {
+            Some(shared) if shared == local => {
+                self.local_turn = !self.local_turn;
+                self.local_turn
+            }
+            Some(shared) => local < shared,
+            None => true,
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        loop {
+            // Tasks woken meanwhile may have a higher priority
+            self.queue_ready_tasks();
+            if self.local_goes_next() {
+                if let Some(task) = self.local_ready.pop() {
+                    self.run_local_task(task);
+                }
+                continue;
+            }
+            let task = self.worker.run_queue.lock().pop();
+            match task.or_else(|| self.steal()) {
+                Some(task) => self.run_task(task),
+                None => break,
+            }
+        }
+    }
+
+    fn run_task(&mut self, mut task: Task) {
+        let task_id = task.id;
+        let waker = self.waker_for(&mut task);
+        let stats = task.stats.clone();
+        let poll = self.poll_as_current(&stats, &waker, |cx| task.poll(cx));
+        if poll.is_ready() {
+            // Task is done, its waker goes with it
+            return;
+        }
+        // Task isn't done, back to waiting list
+        if self.waiting_tasks.insert(task_id, task).is_some() {
+            panic!("Task with same ID already in waiting_tasks! Literally how");
+        }
+    }
+
+    fn run_local_task(&mut self, mut task: LocalTask) {
+        let task_id = task.id;
+        let stats = task.stats.clone();
+        let waker = task
+            .waker
+            .get_or_insert_with(|| self.create_waker(task_id, &stats))
+            .clone();
+        let poll = self.poll_as_current(&stats, &waker, |cx| task.poll(cx));
+        if poll.is_ready() {
+            return;
+        }
+        if self.local_waiting.insert(task_id, task).is_some() {
+            panic!("Task with same ID already in local_waiting! Literally how");
+        }
+    }
+
+    /// Polls a task through `poll`, as the current task of this executor,
+    /// and records the poll in its statistics. Polls slower than
+    /// `stats::SLOW_POLL` are logged.
+    fn poll_as_current(
+        &self,
+        stats: &Stats,
+        waker: &Waker,
+        poll: impl FnOnce(&mut Context) -> Poll<()>,
+    ) -> Poll<()> {
+        let mut context = Context::from_waker(waker);
+        let task_id = stats.id();
+        let spawns: *const LocalSpawns = &*self.local_spawns;
+        CURRENT_TASK.get().store(task_id.0, Ordering::Relaxed);
+        POLLING.get().store(spawns as *mut _, Ordering::Relaxed);
+        let start = stats.poll_started(self.worker.cpu);
+        let result = poll(&mut context);
+        let duration = stats.poll_finished(start, result.is_pending());
+        POLLING.get().store(ptr::null_mut(), Ordering::Relaxed);
+        CURRENT_TASK.get().store(NO_TASK, Ordering::Relaxed);
+        if duration >= stats::SLOW_POLL {
+            log::warn!(
+                "task {} ({}) was polled for {:?}",
+                task_id,
+                stats.name().unwrap_or("unnamed"),
+                duration
+            );
+        }
+        result
+    }
 }
 
 #[cfg(test)]
@@ -168,6 +713,70 @@ use crate::{serial_print, serial_println};
 fn test_task_spawn_exec() {
     serial_print!("test_task_spawn_exec... ");
     let executor = Executor::new().spawn(Task::new(async {}));
-    assert!(executor.task_queue.front().is_some());
+    assert!(executor.worker.run_queue.lock().pop().is_some());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_task_priority_aging() {
+    serial_print!("test_task_priority_aging... ");
+    let mut run_queue = RunQueue::new();
+    run_queue.push(Priority::Background, Priority::Background);
+    run_queue.push(Priority::Interactive, Priority::Interactive);
+    for _ in 0..2 * MAX_STARVATION {
+        run_queue.push(Priority::BottomHalf, Priority::BottomHalf);
+    }
+
+    let mut order = Vec::new();
+    while let Some(priority) = run_queue.pop() {
+        order.push(priority);
+    }
+    // Higher classes first, until the others have waited long enough
+    assert!(order[..MAX_STARVATION]
+        .iter()
+        .all(|&p| p == Priority::BottomHalf));
+    assert_eq!(order[MAX_STARVATION], Priority::Interactive);
+    assert_eq!(order[MAX_STARVATION + 1], Priority::Background);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_run_queue_steal() {
+    serial_print!("test_run_queue_steal... ");
+    let mut victim = RunQueue::new();
+    for _ in 0..3 {
+        victim.push(Priority::default(), ());
+    }
+    let mut thief = RunQueue::new();
+    victim.steal_into(&mut thief);
+    assert_eq!(victim.len(), 1);
+    assert_eq!(thief.len(), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_spawn_local() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    serial_print!("test_spawn_local... ");
+    // Only tasks polled by an executor can spawn local ones
+    assert!(matches!(spawn_local(async {}), Err(SpawnError::NotInTask)));
+
+    // `Rc` isn't `Send`, so this could only ever be a local task
+    let result = Rc::new(Cell::new(0));
+    let inner = result.clone();
+    let mut executor = Executor::new().spawn_local(LocalTask::new(async move {
+        let value = Rc::new(42);
+        let handle = spawn_local(async move { *value }).unwrap();
+        inner.set(handle.await.unwrap());
+    }));
+    for _ in 0..3 {
+        executor.queue_ready_tasks();
+        if let Some(task) = executor.local_ready.pop() {
+            executor.run_local_task(task);
+        }
+    }
+    assert_eq!(result.get(), 42);
     serial_println!("[ok]");
 }
@@ -0,0 +1,154 @@
+//! Awaiting the output of spawned tasks.
+use super::{AbortHandle, LocalTask, Priority, Task, TaskId};
+use alloc::sync::Arc;
+use core::{
+    future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Error returned by an awaited `JoinHandle`.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The task was aborted before it completed.
+    Aborted,
+}
+
+/// Output of a task, shared between the task and its `JoinHandle`.
+struct Shared<T> {
+    output: Mutex<Option<T>>,
+    finished: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Marks the task finished when dropped, whether it completed or was
+/// aborted.
+struct Finish<T>(Arc<Shared<T>>);
+
+impl<T> Drop for Finish<T> {
+    fn drop(&mut self) {
+        self.0.finished.store(true, Ordering::Release);
+        self.0.waker.wake();
+    }
+}
+
+/// Handle to a spawned task, resolves to its output when awaited.
+///
+/// Dropping the handle detaches the task, which keeps running and drops its
+/// output when done.
+#[must_use = "dropping a `JoinHandle` detaches the task, use `detach` to make that explicit"]
+pub struct JoinHandle<T> {
+    id: TaskId,
+    abort: AbortHandle,
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the ID of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns a handle that can abort the task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+
+    /// Aborts the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Whether the task has completed or was aborted.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+
+    /// Lets the task run on its own.
+    pub fn detach(self) {}
+}
+
+impl<T> future::Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    /// Resolves to the output of the task, or `JoinError::Aborted` if the
+    /// task was aborted or the output was already taken.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if !self.is_finished() {
+            self.shared.waker.register(cx.waker());
+            if !self.is_finished() {
+                return Poll::Pending;
+            }
+        }
+        let output = self.shared.output.lock().take();
+        Poll::Ready(output.ok_or(JoinError::Aborted))
+    }
+}
+
+/// Wraps `future` so it stores its output in the returned state.
+fn store_output<F: future::Future>(
+    future: F,
+) -> (impl future::Future<Output = ()>, Arc<Shared<F::Output>>) {
+    let shared = Arc::new(Shared {
+        output: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    let finish = Finish(shared.clone());
+    let future = async move {
+        let output = future.await;
+        *finish.0.output.lock() = Some(output);
+    };
+    (future, shared)
+}
+
+/// Wraps `future` in a task that stores its output for the returned handle.
+pub(super) fn joinable<F>(future: F, priority: Priority) -> (Task, JoinHandle<F::Output>)
+where
+    F: future::Future + Send + Sync + 'static,
+    F::Output: Send + 'static,
+{
+    let (future, shared) = store_output(future);
+    let task = Task::with_priority(future, priority);
+    let handle = JoinHandle {
+        id: task.id,
+        abort: task.abort_handle(),
+        shared,
+    };
+    (task, handle)
+}
+
+/// Like `joinable`, for local tasks.
+pub(super) fn joinable_local<F>(future: F, priority: Priority) -> (LocalTask, JoinHandle<F::Output>)
+where
+    F: future::Future + 'static,
+{
+    let (future, shared) = store_output(future);
+    let task = LocalTask::with_priority(future, priority);
+    let handle = JoinHandle {
+        id: task.id,
+        abort: task.abort_handle(),
+        shared,
+    };
+    (task, handle)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_join_handle() {
+    use super::simple_executor::SimpleExecutor;
+    use futures_util::future::FutureExt;
+
+    serial_print!("test_join_handle... ");
+    let (task, handle) = joinable(async { 6 * 7 }, Priority::default());
+    assert!(!handle.is_finished());
+    SimpleExecutor::new().spawn(task).run();
+    assert!(handle.is_finished());
+    assert!(matches!(handle.now_or_never(), Some(Ok(42))));
+    serial_println!("[ok]");
+}
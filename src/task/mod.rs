@@ -1,21 +1,40 @@
 //! Implements simple `Future` based `Task`s.
-use alloc::boxed::Box;
+use abort::AbortState;
+use alloc::{borrow::Cow, boxed::Box, sync::Arc};
 use core::sync::atomic::{AtomicU64, Ordering};
-use core::task::{Context, Poll};
-use core::{future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use core::{fmt, future, pin::Pin};
+use stats::Stats;
 
+pub mod abort;
+pub mod deferred;
 pub mod executor;
+pub mod join;
 pub mod simple_executor;
+pub mod stats;
+pub mod sync;
+pub mod timer;
 
-pub use executor::{spawn_task as spawn, Executor};
+pub use abort::{AbortHandle, TaskGroup};
+pub use executor::{
+    current_task, spawn, spawn_local, spawn_local_task, spawn_task, spawn_with_priority, Builder,
+    Executor,
+};
+pub use join::{JoinError, JoinHandle};
+pub use stats::{TaskInfo, TaskState};
+pub use timer::{interval, sleep, sleep_until, timeout, Interval};
 
 /// Stores a unique ID that is used by executors.
+#[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 /// Trait alias for convenience.
 pub trait Future = future::Future<Output = ()> + Send + Sync;
 
+/// Futures of `LocalTask`s, which don't have to be `Send` or `Sync`.
+pub trait LocalFuture = future::Future<Output = ()>;
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -23,22 +42,172 @@ impl TaskId {
     }
 }
 
-/// A task. Contains a `Future` and a `TaskId`.
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Scheduling class of a task. Executors run ready tasks of a higher class
+/// first, lower classes still get a turn now and then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Consumers of interrupt events, like the keyboard.
+    BottomHalf,
+    /// Tasks that someone is waiting on.
+    Interactive,
+    /// Work that can wait.
+    Background,
+}
+
+impl Priority {
+    /// Number of priority classes.
+    pub const COUNT: usize = 3;
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Interactive
+    }
+}
+
+/// A task. Contains a `Future`, a `TaskId`, a `Priority`, whether it has
+/// been aborted and its statistics.
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    abort: Arc<AbortState>,
+    stats: Arc<Stats>,
+    /// Waker of the executor that polled the task last, with its CPU.
+    waker: Option<(usize, Waker)>,
     future: Pin<Box<dyn Future>>,
 }
 
 impl Task {
-    /// Creates a new `Task`.
+    /// Creates a new `Task` with the default priority.
     pub fn new(future: impl Future + 'static) -> Task {
+        Task::with_priority(future, Priority::default())
+    }
+
+    /// Creates a new `Task` with the given priority.
+    pub fn with_priority(future: impl Future + 'static, priority: Priority) -> Task {
+        let id = TaskId::new();
         Task {
-            id: TaskId::new(),
+            id,
+            priority,
+            abort: Arc::new(AbortState::new()),
+            stats: Arc::new(Stats::new(id, None, priority, false)),
+            waker: None,
             future: Box::pin(future),
         }
     }
 
+    /// Names the task, the name shows up in its statistics.
+    pub fn named(mut self, name: impl Into<Cow<'static, str>>) -> Task {
+        // Nothing has seen the statistics before the task is spawned
+        self.stats = Arc::new(Stats::new(self.id, Some(name.into()), self.priority, false));
+        self
+    }
+
+    /// Returns the ID of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns the name of the task, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.stats.name()
+    }
+
+    /// Returns the priority of the task.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Returns a handle that can abort the task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle::new(self.abort.clone())
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        poll_abortable(&self.abort, self.future.as_mut(), context)
+    }
+}
+
+/// A task that stays on the executor of the CPU it was spawned on, so its
+/// future doesn't have to be `Send`. Meant for code that owns per-CPU state.
+pub struct LocalTask {
+    id: TaskId,
+    priority: Priority,
+    abort: Arc<AbortState>,
+    stats: Arc<Stats>,
+    waker: Option<Waker>,
+    future: Pin<Box<dyn LocalFuture>>,
+}
+
+impl LocalTask {
+    /// Creates a new `LocalTask` with the default priority.
+    pub fn new(future: impl LocalFuture + 'static) -> LocalTask {
+        LocalTask::with_priority(future, Priority::default())
+    }
+
+    /// Creates a new `LocalTask` with the given priority.
+    pub fn with_priority(future: impl LocalFuture + 'static, priority: Priority) -> LocalTask {
+        let id = TaskId::new();
+        LocalTask {
+            id,
+            priority,
+            abort: Arc::new(AbortState::new()),
+            stats: Arc::new(Stats::new(id, None, priority, true)),
+            waker: None,
+            future: Box::pin(future),
+        }
+    }
+
+    /// Names the task, the name shows up in its statistics.
+    pub fn named(mut self, name: impl Into<Cow<'static, str>>) -> LocalTask {
+        self.stats = Arc::new(Stats::new(self.id, Some(name.into()), self.priority, true));
+        self
+    }
+
+    /// Returns the ID of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns the name of the task, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.stats.name()
+    }
+
+    /// Returns the priority of the task.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Returns a handle that can abort the task.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle::new(self.abort.clone())
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        poll_abortable(&self.abort, self.future.as_mut(), context)
+    }
+}
+
+/// Polls `future`, or completes without polling it if the task was aborted.
+/// Executors then drop the task like any completed one.
+fn poll_abortable<F>(abort: &AbortState, future: Pin<&mut F>, context: &mut Context) -> Poll<()>
+where
+    F: future::Future<Output = ()> + ?Sized,
+{
+    if abort.is_aborted() {
+        return Poll::Ready(());
+    }
+    abort.waker.register(context.waker());
+    // `abort` may have missed the waker
+    if abort.is_aborted() {
+        return Poll::Ready(());
     }
+    future.poll(context)
 }
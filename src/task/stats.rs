@@ -0,0 +1,230 @@
+//! Per-task runtime statistics.
+//!
+//! Executors record every poll and wakeup of the tasks they run. `snapshot`
+//! copies the statistics of all spawned tasks that haven't finished, for a
+//! `ps` like view, and `hogs` picks the ones that kept a CPU busy too long.
+use super::{Priority, TaskId};
+use crate::{
+    arch::woint,
+    time::{Duration, Instant},
+};
+use alloc::{
+    borrow::Cow,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+/// Polls taking longer than this are logged, they keep other tasks on the
+/// CPU from running.
+pub const SLOW_POLL: Duration = Duration::from_millis(10);
+
+/// No CPU has polled the task yet.
+const NO_CPU: usize = usize::MAX;
+
+/// Statistics of the spawned tasks, dropped ones are pruned lazily.
+static TASKS: Mutex<Vec<Weak<Stats>>> = Mutex::new(Vec::new());
+
+/// What a task is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken or spawned, waiting for its turn.
+    Ready,
+    /// Being polled.
+    Running,
+    /// Waiting for its waker.
+    Waiting,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+/// Statistics of a task at the time of a `snapshot`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<Cow<'static, str>>,
+    pub priority: Priority,
+    /// Whether it is a `LocalTask`, which never leaves its CPU.
+    pub local: bool,
+    pub state: TaskState,
+    /// CPU that polled the task last.
+    pub cpu: Option<usize>,
+    pub polls: u64,
+    pub wakes: u64,
+    /// Total time spent polling the task.
+    pub busy: Duration,
+    /// Longest single poll.
+    pub max_poll: Duration,
+}
+
+/// Statistics of a task, shared by the task and the wakers of executors.
+pub(super) struct Stats {
+    id: TaskId,
+    name: Option<Cow<'static, str>>,
+    priority: Priority,
+    local: bool,
+    state: AtomicU8,
+    cpu: AtomicUsize,
+    polls: AtomicU64,
+    wakes: AtomicU64,
+    busy_nanos: AtomicU64,
+    max_poll_nanos: AtomicU64,
+}
+
+impl Stats {
+    pub(super) fn new(
+        id: TaskId,
+        name: Option<Cow<'static, str>>,
+        priority: Priority,
+        local: bool,
+    ) -> Self {
+        Stats {
+            id,
+            name,
+            priority,
+            local,
+            state: AtomicU8::new(TaskState::Ready as u8),
+            cpu: AtomicUsize::new(NO_CPU),
+            polls: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            max_poll_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub(super) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Counts a wakeup. Safe to call from interrupt handlers.
+    pub(super) fn woken(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        self.state.store(TaskState::Ready as u8, Ordering::Relaxed);
+    }
+
+    /// Marks the task as being polled on `cpu`, returns when the poll
+    /// started.
+    pub(super) fn poll_started(&self, cpu: usize) -> Instant {
+        self.state
+            .store(TaskState::Running as u8, Ordering::Relaxed);
+        self.cpu.store(cpu, Ordering::Relaxed);
+        Instant::now()
+    }
+
+    /// Records a poll that started at `start` and returns how long it took.
+    /// `pending` tells whether the task waits for a wakeup now.
+    pub(super) fn poll_finished(&self, start: Instant, pending: bool) -> Duration {
+        let duration = start.elapsed();
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_poll_nanos.fetch_max(nanos, Ordering::Relaxed);
+        if pending {
+            // Stays ready if it was woken while running
+            let _ = self.state.compare_exchange(
+                TaskState::Running as u8,
+                TaskState::Waiting as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        duration
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            priority: self.priority,
+            local: self.local,
+            state: TaskState::from_u8(self.state.load(Ordering::Relaxed)),
+            cpu: match self.cpu.load(Ordering::Relaxed) {
+                NO_CPU => None,
+                cpu => Some(cpu),
+            },
+            polls: self.polls.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+            max_poll: Duration::from_nanos(self.max_poll_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Adds the statistics of a task that is being spawned to the table.
+pub(super) fn register(stats: &Arc<Stats>) {
+    let stats = Arc::downgrade(stats);
+    woint(|| {
+        let mut tasks = TASKS.lock();
+        // Forget tasks that are gone
+        tasks.retain(|task| task.strong_count() > 0);
+        tasks.push(stats);
+    });
+}
+
+/// Returns the statistics of all spawned tasks that haven't finished, in
+/// the order they were spawned.
+pub fn snapshot() -> Vec<TaskInfo> {
+    let tasks: Vec<_> = woint(|| TASKS.lock().iter().filter_map(Weak::upgrade).collect());
+    tasks.iter().map(|stats| stats.info()).collect()
+}
+
+/// Returns the tasks with a poll that took at least `threshold`, longest
+/// first.
+pub fn hogs(threshold: Duration) -> Vec<TaskInfo> {
+    let mut hogs: Vec<_> = snapshot()
+        .into_iter()
+        .filter(|task| task.max_poll >= threshold)
+        .collect();
+    hogs.sort_by_key(|task| core::cmp::Reverse(task.max_poll));
+    hogs
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_task_stats() {
+    serial_print!("test_task_stats... ");
+    let stats = Arc::new(Stats::new(
+        TaskId::new(),
+        Some("test".into()),
+        Priority::default(),
+        false,
+    ));
+    register(&stats);
+    let start = stats.poll_started(0);
+    stats.poll_finished(start, true);
+    stats.woken();
+    let start = stats.poll_started(1);
+    // Woken while running, so it is still ready
+    stats.woken();
+    stats.poll_finished(start, true);
+
+    let id = stats.id();
+    let find = || snapshot().into_iter().find(|task| task.id == id);
+    let info = find().unwrap();
+    assert_eq!(info.name.as_deref(), Some("test"));
+    assert_eq!(info.state, TaskState::Ready);
+    assert_eq!(info.cpu, Some(1));
+    assert_eq!((info.polls, info.wakes), (2, 2));
+    assert!(info.max_poll <= info.busy);
+    drop(stats);
+    assert!(find().is_none());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,244 @@
+//! Multi-producer, multi-consumer channels where every receiver sees every
+//! value.
+//!
+//! The channel keeps the last `capacity` values. A receiver that falls
+//! further behind misses the oldest ones and is told how many.
+use super::locked;
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+
+/// Error returned when sending on a channel without receivers. Contains the
+/// value that couldn't be sent.
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `Receiver::recv`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind and missed this many values, the next call
+    /// returns the oldest one still kept.
+    Lagged(usize),
+    /// All senders are gone and the receiver saw every value.
+    Closed,
+}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The receiver saw every value, but senders are left.
+    Empty,
+    /// See `RecvError::Lagged`.
+    Lagged(usize),
+    /// See `RecvError::Closed`.
+    Closed,
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    /// Sequence number of the first value in `buffer`.
+    head: usize,
+    senders: usize,
+    receivers: usize,
+    /// Receivers waiting for the next value.
+    wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    /// Sequence number of the next value sent.
+    fn tail(&self) -> usize {
+        self.head + self.buffer.len()
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared<T> {
+    state: spin::Mutex<State<T>>,
+    capacity: usize,
+}
+
+/// Creates a channel that keeps the last `capacity` values for lagging
+/// receivers. More receivers can be made with `Sender::subscribe`.
+///
+/// # Panics
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            head: 0,
+            senders: 1,
+            receivers: 1,
+            wakers: Vec::new(),
+        }),
+        capacity,
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+/// Sending side of a broadcast channel.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` to all receivers, returns how many there are. Doesn't
+    /// allocate, so it can be used from interrupt handlers.
+    ///
+    /// # Errors
+    /// Returns the value if there are no receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let capacity = self.shared.capacity;
+        locked(&self.shared.state, |state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.buffer.len() == capacity {
+                state.buffer.pop_front();
+                state.head += 1;
+            }
+            state.buffer.push_back(value);
+            state.wake_all();
+            Ok(state.receivers)
+        })
+    }
+
+    /// Creates a receiver that sees the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = locked(&self.shared.state, |state| {
+            state.receivers += 1;
+            state.tail()
+        });
+        Receiver {
+            shared: self.shared.clone(),
+            next,
+        }
+    }
+
+    /// Returns the number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        locked(&self.shared.state, |state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        locked(&self.shared.state, |state| state.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        locked(&self.shared.state, |state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.wake_all();
+            }
+        });
+    }
+}
+
+/// Receiving side of a broadcast channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Sequence number of the next value to receive.
+    next: usize,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for the next value.
+    ///
+    /// # Errors
+    /// Returns an error if the receiver lagged behind or the channel is
+    /// closed.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Takes the next value if there is one.
+    ///
+    /// # Errors
+    /// Returns an error if no value is waiting, the receiver lagged behind
+    /// or the channel is closed.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next = &mut self.next;
+        locked(&self.shared.state, |state| try_recv_locked(next, state))
+    }
+
+    /// Polls for the next value, see `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let next = &mut self.next;
+        locked(&self.shared.state, |state| {
+            match try_recv_locked(next, state) {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+                Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Empty) => {
+                    if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        state.wakers.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+/// Takes the value with sequence number `next` and moves on to the one after.
+fn try_recv_locked<T: Clone>(next: &mut usize, state: &mut State<T>) -> Result<T, TryRecvError> {
+    if *next < state.head {
+        let missed = state.head - *next;
+        *next = state.head;
+        return Err(TryRecvError::Lagged(missed));
+    }
+    match state.buffer.get(*next - state.head) {
+        Some(value) => {
+            *next += 1;
+            Ok(value.clone())
+        }
+        None if state.senders == 0 => Err(TryRecvError::Closed),
+        None => Err(TryRecvError::Empty),
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        locked(&self.shared.state, |state| state.receivers -= 1);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_broadcast_lagged() {
+    serial_print!("test_broadcast_lagged... ");
+    let (sender, mut first) = channel(2);
+    let mut second = sender.subscribe();
+    for value in 0..3 {
+        assert_eq!(sender.send(value).unwrap(), 2);
+    }
+    assert_eq!(second.try_recv(), Err(TryRecvError::Lagged(1)));
+    assert_eq!(second.try_recv(), Ok(1));
+    assert_eq!(second.try_recv(), Ok(2));
+    assert_eq!(second.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(first.try_recv(), Err(TryRecvError::Lagged(1)));
+    drop(sender);
+    assert_eq!(first.try_recv(), Ok(1));
+    assert_eq!(second.try_recv(), Err(TryRecvError::Closed));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,89 @@
+//! Async synchronization primitives for tasks.
+//!
+//! Unlike `spin::Mutex`, waiting for these only suspends the task and lets
+//! the executor run others. Waiters are served in the order they started
+//! waiting.
+//!
+//! Internal state is only locked with interrupts disabled and wakers are
+//! called with it locked, so they must not use the primitive they wake for.
+//! Whatever doesn't wait or allocate can be used from interrupt handlers,
+//! like `Notify::notify_one`, `Semaphore::add_permits`,
+//! `mpsc::Sender::try_send`, `oneshot::Sender::send` and
+//! `broadcast::Sender::send`.
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
+
+use crate::arch::woint;
+use alloc::sync::Arc;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::Waker,
+};
+
+/// Runs `f` on the locked state, with interrupts disabled.
+fn locked<T, R>(lock: &spin::Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R {
+    woint(|| f(&mut lock.lock()))
+}
+
+/// A task waiting in one of the queues of this module.
+struct Waiter {
+    /// What the task waits for, like the number of permits.
+    amount: usize,
+    woken: AtomicBool,
+    waker: spin::Mutex<Option<Waker>>,
+}
+
+impl Waiter {
+    fn new(amount: usize, waker: &Waker) -> Arc<Self> {
+        Arc::new(Waiter {
+            amount,
+            woken: AtomicBool::new(false),
+            waker: spin::Mutex::new(Some(waker.clone())),
+        })
+    }
+
+    /// Whether the waiter got what it waits for.
+    fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    /// Updates the waker if the task moved.
+    fn set_waker(&self, waker: &Waker) {
+        let mut current = self.waker.lock();
+        if !current
+            .as_ref()
+            .map_or(false, |current| current.will_wake(waker))
+        {
+            *current = Some(waker.clone());
+        }
+    }
+
+    /// Marks the waiter woken and wakes its task.
+    fn wake(&self) {
+        self.woken.store(true, Ordering::Release);
+        self.wake_task();
+    }
+
+    /// Wakes the task without marking the waiter, so it looks around itself.
+    fn wake_task(&self) {
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Removes `waiter` from `waiters`, if it is still there.
+fn remove_waiter(waiters: &mut alloc::vec::Vec<Arc<Waiter>>, waiter: &Arc<Waiter>) {
+    waiters.retain(|other| !Arc::ptr_eq(other, waiter));
+}
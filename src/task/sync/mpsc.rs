@@ -0,0 +1,285 @@
+//! Multi-producer, single-consumer channels.
+//!
+//! Bounded channels allocate their buffer up front, so `Sender::try_send`
+//! works from interrupt handlers. Unbounded ones grow as needed.
+use super::{locked, Semaphore, TryAcquireError};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{future::poll_fn, stream::Stream, task::AtomicWaker};
+
+/// Error returned when sending on a channel whose receiver is gone. Contains
+/// the value that couldn't be sent.
+#[derive(Debug)]
+pub struct SendError<T>(pub T);
+
+/// Error returned by `Sender::try_send`, contains the value that couldn't be
+/// sent.
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No values are waiting, but senders are left.
+    Empty,
+    /// No values are waiting and all senders are gone.
+    Disconnected,
+}
+
+struct Chan<T> {
+    queue: spin::Mutex<VecDeque<T>>,
+    bounded: bool,
+    /// Free slots of bounded channels.
+    slots: Semaphore,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+    receiver_waker: AtomicWaker,
+}
+
+impl<T> Chan<T> {
+    fn new(capacity: Option<usize>) -> Arc<Self> {
+        Arc::new(Chan {
+            queue: spin::Mutex::new(capacity.map_or_else(VecDeque::new, VecDeque::with_capacity)),
+            bounded: capacity.is_some(),
+            slots: Semaphore::new(capacity.unwrap_or(0)),
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+            receiver_waker: AtomicWaker::new(),
+        })
+    }
+
+    fn push(&self, value: T) {
+        locked(&self.queue, |queue| queue.push_back(value));
+        self.receiver_waker.wake();
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = locked(&self.queue, VecDeque::pop_front)?;
+        if self.bounded {
+            self.slots.add_permits(1);
+        }
+        Some(value)
+    }
+
+    fn add_sender(self: &Arc<Self>) -> Arc<Self> {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+        self.clone()
+    }
+
+    fn drop_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.receiver_waker.wake();
+        }
+    }
+}
+
+/// Creates a channel holding up to `capacity` values.
+///
+/// # Panics
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let chan = Chan::new(Some(capacity));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Creates a channel without a limit on waiting values.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Sending side of a bounded channel.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Waits for a free slot and sends `value`.
+    ///
+    /// # Errors
+    /// Returns the value if the receiver is gone.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.chan.slots.acquire().await {
+            Ok(permit) => {
+                // Given back by the receiver
+                permit.forget();
+                self.chan.push(value);
+                Ok(())
+            }
+            Err(_) => Err(SendError(value)),
+        }
+    }
+
+    /// Sends `value` if there is a free slot.
+    ///
+    /// # Errors
+    /// Returns the value if the channel is full or the receiver is gone.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.slots.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                self.chan.push(value);
+                Ok(())
+            }
+            Err(TryAcquireError::NoPermits) => Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => Err(TrySendError::Closed(value)),
+        }
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            chan: self.chan.add_sender(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Sending side of an unbounded channel.
+pub struct UnboundedSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Sends `value`. Allocates, so it can't be used from interrupt
+    /// handlers.
+    ///
+    /// # Errors
+    /// Returns the value if the receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.is_closed() {
+            return Err(SendError(value));
+        }
+        self.chan.push(value);
+        Ok(())
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        UnboundedSender {
+            chan: self.chan.add_sender(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+/// Receiving side of a channel.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value. Returns `None` once all senders are gone
+    /// and no values are left.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Takes the next value if there is one.
+    ///
+    /// # Errors
+    /// Returns an error if no values are waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.pop() {
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // Sent right before the last sender was dropped
+            self.chan.pop().ok_or(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Polls for the next value, see `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.chan.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Stops accepting values, ones already sent can still be received.
+    pub fn close(&mut self) {
+        self.chan.closed.store(true, Ordering::Release);
+        self.chan.slots.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+        // Drop the values now, not when the last sender is gone
+        let values = locked(&self.chan.queue, core::mem::take);
+        drop(values);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_mpsc_bounded() {
+    use futures_util::future::FutureExt;
+
+    serial_print!("test_mpsc_bounded... ");
+    let (sender, mut receiver) = channel(1);
+    sender.try_send(1).unwrap();
+    assert!(matches!(sender.try_send(2), Err(TrySendError::Full(2))));
+    let mut send = sender.send(2).boxed();
+    assert!(send.as_mut().now_or_never().is_none());
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert!(send.now_or_never().unwrap().is_ok());
+    drop(sender);
+    assert_eq!(receiver.recv().now_or_never(), Some(Some(2)));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    serial_println!("[ok]");
+}
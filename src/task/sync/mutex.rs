@@ -0,0 +1,119 @@
+//! Async mutex.
+use super::Semaphore;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// Mutual exclusion lock that suspends the task while it is held by
+/// another.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex holding `value`.
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Waits until the mutex is unlocked and locks it.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        match self.semaphore.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => unreachable!("mutex semaphore is never closed"),
+        }
+        MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Locks the mutex if it is unlocked and no one is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire().ok()?;
+        permit.forget();
+        Some(MutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the value, no locking needed as the borrow is exclusive.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("value", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+/// Lock on a `Mutex`, unlocks it when dropped.
+#[allow(clippy::module_name_repetitions)]
+#[must_use = "dropping the guard unlocks the mutex right away"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    /// Only `Sync` if `T` is.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_mutex() {
+    use futures_util::future::FutureExt;
+
+    serial_print!("test_mutex... ");
+    let mutex = Mutex::new(0);
+    let mut guard = mutex.lock().now_or_never().unwrap();
+    *guard += 1;
+    let mut waiting = mutex.lock().boxed();
+    assert!(waiting.as_mut().now_or_never().is_none());
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*waiting.now_or_never().unwrap(), 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,124 @@
+//! Notifying tasks of events.
+use super::{locked, remove_waiter, Waiter};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+struct State {
+    /// Left by `notify_one` when no one was waiting.
+    permit: bool,
+    /// Bumped by `notify_waiters`.
+    generation: usize,
+    waiters: Vec<Arc<Waiter>>,
+}
+
+/// Wakes tasks waiting for an event, without carrying any data.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification. The task only counts as waiting once the
+    /// future is polled.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+            generation: 0,
+        }
+    }
+
+    /// Wakes the task that has been waiting the longest. If none is, the
+    /// next one to wait returns immediately.
+    pub fn notify_one(&self) {
+        locked(&self.state, |state| {
+            if state.waiters.is_empty() {
+                state.permit = true;
+            } else {
+                state.waiters.remove(0).wake();
+            }
+        });
+    }
+
+    /// Wakes all waiting tasks, doesn't affect ones that wait later.
+    pub fn notify_waiters(&self) {
+        locked(&self.state, |state| {
+            state.generation = state.generation.wrapping_add(1);
+            for waiter in state.waiters.drain(..) {
+                waiter.wake_task();
+            }
+        });
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+    /// Generation when the task started waiting.
+    generation: usize,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let notified = locked(&this.notify.state, |state| {
+            if let Some(waiter) = &this.waiter {
+                if waiter.is_woken() || state.generation != this.generation {
+                    return true;
+                }
+                waiter.set_waker(cx.waker());
+                return false;
+            }
+
+            if state.permit {
+                state.permit = false;
+                return true;
+            }
+            let waiter = Waiter::new(1, cx.waker());
+            state.waiters.push(waiter.clone());
+            this.waiter = Some(waiter);
+            this.generation = state.generation;
+            false
+        });
+
+        if notified {
+            this.waiter = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            locked(&self.notify.state, |state| {
+                if !waiter.is_woken() {
+                    remove_waiter(&mut state.waiters, &waiter);
+                } else if state.waiters.is_empty() {
+                    // Pass on the `notify_one` no one saw
+                    state.permit = true;
+                } else {
+                    state.waiters.remove(0).wake();
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Channels for sending a single value.
+use super::locked;
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::task::AtomicWaker;
+
+/// Error returned by an awaited `Receiver` if the sender was dropped without
+/// sending.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing was sent yet.
+    Empty,
+    /// The sender was dropped without sending.
+    Closed,
+}
+
+struct Inner<T> {
+    value: spin::Mutex<Option<T>>,
+    /// Set once the sender sent or is gone.
+    complete: AtomicBool,
+    /// Set once the receiver is gone.
+    closed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Creates a channel for a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        value: spin::Mutex::new(None),
+        complete: AtomicBool::new(false),
+        closed: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+/// Sending side of a oneshot channel.
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` to the receiver. Doesn't allocate, so it can be used
+    /// from interrupt handlers.
+    ///
+    /// # Errors
+    /// Returns the value if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+        locked(&self.inner.value, |slot| *slot = Some(value));
+        // Dropping `self` completes the channel
+        Ok(())
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.complete.store(true, Ordering::Release);
+        self.inner.waker.wake();
+    }
+}
+
+/// Receiving side of a oneshot channel, resolves to the value when awaited.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the value if it was sent.
+    ///
+    /// # Errors
+    /// Returns an error if nothing was sent yet or the sender is gone.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if !self.inner.complete.load(Ordering::Acquire) {
+            return Err(TryRecvError::Empty);
+        }
+        locked(&self.inner.value, Option::take).ok_or(TryRecvError::Closed)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        this.inner.waker.register(cx.waker());
+        match this.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_oneshot() {
+    use futures_util::future::FutureExt;
+
+    serial_print!("test_oneshot... ");
+    let (sender, mut receiver) = channel();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    sender.send(42).unwrap();
+    assert_eq!(receiver.now_or_never(), Some(Ok(42)));
+
+    let (sender, receiver) = channel::<()>();
+    drop(sender);
+    assert_eq!(receiver.now_or_never(), Some(Err(RecvError)));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,268 @@
+//! Counting semaphore.
+use super::{locked, remove_waiter, Waiter};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Error returned when acquiring from a closed `Semaphore`.
+#[derive(Debug)]
+pub struct AcquireError;
+
+/// Error returned by `Semaphore::try_acquire`.
+#[derive(Debug)]
+pub enum TryAcquireError {
+    /// Not enough permits are available right now.
+    NoPermits,
+    /// The semaphore was closed.
+    Closed,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    waiters: Vec<Arc<Waiter>>,
+}
+
+impl State {
+    /// Hands out permits to waiters in order, up to the first one there
+    /// aren't enough permits for.
+    fn grant(&mut self) {
+        while let Some(waiter) = self.waiters.first() {
+            if waiter.amount > self.permits {
+                break;
+            }
+            self.permits -= waiter.amount;
+            self.waiters.remove(0).wake();
+        }
+    }
+}
+
+/// Counting semaphore with FIFO waiters. A task asking for many permits
+/// holds up the ones queued after it, so it isn't starved.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+impl Semaphore {
+    /// Most permits a semaphore can hold.
+    pub const MAX_PERMITS: usize = usize::MAX >> 3;
+
+    /// Creates a semaphore with `permits` available, at most `MAX_PERMITS`.
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits: if permits > Self::MAX_PERMITS {
+                    Self::MAX_PERMITS
+                } else {
+                    permits
+                },
+                closed: false,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of permits that can be acquired.
+    pub fn available_permits(&self) -> usize {
+        locked(&self.state, |state| state.permits)
+    }
+
+    /// Waits for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits for `permits` permits at once.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            waiter: None,
+        }
+    }
+
+    /// Takes a permit if one is available and no one is waiting.
+    ///
+    /// # Errors
+    /// Returns an error if there are not enough permits or the semaphore is
+    /// closed.
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `permits` permits if they are available and no one is waiting.
+    ///
+    /// # Errors
+    /// Returns an error if there are not enough permits or the semaphore is
+    /// closed.
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        locked(&self.state, |state| {
+            if state.closed {
+                Err(TryAcquireError::Closed)
+            } else if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+                Ok(SemaphorePermit {
+                    semaphore: self,
+                    permits,
+                })
+            } else {
+                Err(TryAcquireError::NoPermits)
+            }
+        })
+    }
+
+    /// Adds `permits` permits, waking waiters they are enough for.
+    ///
+    /// # Panics
+    /// Panics if the semaphore would hold more than `MAX_PERMITS`.
+    pub fn add_permits(&self, permits: usize) {
+        locked(&self.state, |state| {
+            state.permits = state
+                .permits
+                .checked_add(permits)
+                .filter(|&permits| permits <= Self::MAX_PERMITS)
+                .expect("semaphore permits exceed MAX_PERMITS");
+            state.grant();
+        });
+    }
+
+    /// Closes the semaphore. Waiting and later acquires fail, permits that
+    /// were already acquired stay valid.
+    pub fn close(&self) {
+        locked(&self.state, |state| {
+            state.closed = true;
+            for waiter in state.waiters.drain(..) {
+                waiter.wake_task();
+            }
+        });
+    }
+
+    /// Whether the semaphore was closed.
+    pub fn is_closed(&self) -> bool {
+        locked(&self.state, |state| state.closed)
+    }
+}
+
+/// Permits acquired from a `Semaphore`, given back when dropped.
+#[allow(clippy::module_name_repetitions)]
+#[must_use = "dropping a permit gives it back right away"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits out of the semaphore for good.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+/// Future returned by `Semaphore::acquire` and `Semaphore::acquire_many`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let semaphore = this.semaphore;
+        let permits = this.permits;
+        let acquired = locked(&semaphore.state, |state| {
+            if let Some(waiter) = &this.waiter {
+                if waiter.is_woken() {
+                    return Some(true);
+                }
+                if state.closed {
+                    return Some(false);
+                }
+                waiter.set_waker(cx.waker());
+                return None;
+            }
+
+            if state.closed {
+                return Some(false);
+            }
+            if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+                return Some(true);
+            }
+            let waiter = Waiter::new(permits, cx.waker());
+            state.waiters.push(waiter.clone());
+            this.waiter = Some(waiter);
+            None
+        });
+
+        match acquired {
+            Some(acquired) => {
+                this.waiter = None;
+                Poll::Ready(if acquired {
+                    Ok(SemaphorePermit { semaphore, permits })
+                } else {
+                    Err(AcquireError)
+                })
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            locked(&self.semaphore.state, |state| {
+                if waiter.is_woken() {
+                    // Granted, but no one took the permits
+                    state.permits += waiter.amount;
+                } else {
+                    remove_waiter(&mut state.waiters, &waiter);
+                }
+                // The waiter may have held up the ones after it
+                state.grant();
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_semaphore_fifo() {
+    use futures_util::future::FutureExt;
+
+    serial_print!("test_semaphore_fifo... ");
+    let semaphore = Semaphore::new(1);
+    let permit = semaphore.try_acquire().unwrap();
+    let mut many = semaphore.acquire_many(2).boxed();
+    assert!(many.as_mut().now_or_never().is_none());
+    // Queued behind `many`, even though a permit becomes available
+    drop(permit);
+    assert!(matches!(
+        semaphore.try_acquire(),
+        Err(TryAcquireError::NoPermits)
+    ));
+    semaphore.add_permits(1);
+    let permits = many.now_or_never().unwrap().unwrap();
+    assert_eq!(semaphore.available_permits(), 0);
+    drop(permits);
+    assert_eq!(semaphore.available_permits(), 2);
+    assert_eq!(
+        Semaphore::new(usize::MAX).available_permits(),
+        Semaphore::MAX_PERMITS
+    );
+    serial_println!("[ok]");
+}
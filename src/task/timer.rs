@@ -0,0 +1,249 @@
+//! Time based futures.
+//!
+//! Pending `Sleep`s put their deadline and waker into an ordered map. The
+//! timer interrupt calls `advance`, which wakes every task whose deadline
+//! passed. Dropped `Sleep`s take their entry out again.
+use crate::{
+    arch::woint,
+    time::{Duration, Instant},
+};
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::Stream;
+use spin::{Lazy, Mutex};
+
+/// Deadline of a timer, and an ID to tell timers with the same one apart.
+type TimerKey = (Instant, u64);
+
+/// Pending deadlines. Only locked with interrupts disabled.
+static TIMERS: Lazy<Mutex<BTreeMap<TimerKey, Waker>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+fn register(deadline: Instant, waker: Waker) -> TimerKey {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let key = (deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    woint(|| TIMERS.lock().insert(key, waker));
+    super::executor::timer_added();
+    key
+}
+
+fn unregister(key: TimerKey) {
+    // The waker is dropped with interrupts enabled
+    let _waker = woint(|| TIMERS.lock().remove(&key));
+}
+
+/// Returns `instant + duration`, or the latest representable instant if
+/// that overflows.
+fn saturating_add(instant: Instant, duration: Duration) -> Instant {
+    instant
+        .checked_add(duration)
+        .unwrap_or_else(|| Instant::from_nanos(u64::MAX))
+}
+
+/// Wakes the tasks whose deadline passed. Called by the timer interrupt.
+///
+/// Never blocks, so it is safe to call from interrupt handlers.
+pub fn advance() {
+    let now = Instant::now();
+    woint(|| {
+        // Another CPU is registering a timer, the next tick will catch up
+        let mut timers = match TIMERS.try_lock() {
+            Some(timers) => timers,
+            None => return,
+        };
+        while let Some(key) = timers.keys().next().copied() {
+            if key.0 > now {
+                break;
+            }
+            if let Some(waker) = timers.remove(&key) {
+                waker.wake();
+            }
+        }
+    });
+}
+
+/// Returns the earliest pending deadline.
+pub fn next_deadline() -> Option<Instant> {
+    woint(|| TIMERS.lock().keys().next().map(|&(deadline, _)| deadline))
+}
+
+/// Future that completes at a deadline. Created by `sleep` and `sleep_until`.
+pub struct Sleep {
+    deadline: Instant,
+    /// Waker the deadline was last registered with, and its entry.
+    registered: Option<(Waker, TimerKey)>,
+}
+
+impl Sleep {
+    /// Returns the instant this future completes at.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Moves the deadline, reusing the future.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        self.unregister();
+    }
+
+    fn unregister(&mut self) {
+        if let Some((_, key)) = self.registered.take() {
+            unregister(key);
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let registered = self
+            .registered
+            .as_ref()
+            .map_or(false, |(waker, _)| waker.will_wake(cx.waker()));
+        if !registered {
+            // Replaces the entry of the previous waker
+            self.unregister();
+            let key = register(self.deadline, cx.waker().clone());
+            self.registered = Some((cx.waker().clone(), key));
+        }
+        Poll::Pending
+    }
+}
+
+/// Waits until `duration` has elapsed. Durations too long to represent
+/// wait forever.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(saturating_add(Instant::now(), duration))
+}
+
+/// Waits until `deadline` is reached.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        registered: None,
+    }
+}
+
+/// Error returned by `Timeout` when the deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Future that runs another future until a deadline. Created by `timeout`.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // `future` is never moved out of `self`, `sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Runs `future`, giving up if it doesn't complete within `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Stream yielding at a fixed period. Created by `interval`.
+///
+/// If ticks are missed, the next one is scheduled a full period after it is
+/// yielded, instead of yielding the missed ones in a burst.
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration,
+}
+
+impl Interval {
+    /// Returns the period of this interval.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let now = Instant::now();
+                let mut next = saturating_add(self.sleep.deadline(), self.period);
+                if next <= now {
+                    next = saturating_add(now, self.period);
+                }
+                self.sleep.reset(next);
+                Poll::Ready(Some(now))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Returns a stream yielding every `period`, starting one period from now.
+///
+/// # Panics
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "interval period can't be zero");
+    Interval {
+        sleep: sleep(period),
+        period,
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_sleep_elapsed() {
+    use futures_util::task::noop_waker_ref;
+
+    serial_print!("test_sleep_elapsed... ");
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut elapsed = sleep_until(Instant::BOOT);
+    assert_eq!(Pin::new(&mut elapsed).poll(&mut cx), Poll::Ready(()));
+
+    let mut timed_out = timeout(Duration::ZERO, core::future::pending::<()>());
+    assert_eq!(
+        Pin::new(&mut timed_out).poll(&mut cx),
+        Poll::Ready(Err(Elapsed))
+    );
+
+    // Dropping a pending sleep takes its entry out
+    let mut forever = sleep(Duration::MAX);
+    assert_eq!(Pin::new(&mut forever).poll(&mut cx), Poll::Pending);
+    let (_, key) = forever.registered.clone().unwrap();
+    assert!(woint(|| TIMERS.lock().contains_key(&key)));
+    drop(forever);
+    assert!(!woint(|| TIMERS.lock().contains_key(&key)));
+    serial_println!("[ok]");
+}
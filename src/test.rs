@@ -14,6 +14,7 @@ pub fn runner(tests: &[&dyn Fn()]) {
 pub fn panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}\n", crate::backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     #[allow(clippy::empty_loop)]
     loop {}
@@ -0,0 +1,492 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack and they are scheduled round robin from a
+//! global run queue. The timer interrupt ends the time slice of a thread and
+//! the switch happens when the interrupt returns. Threads can also give up
+//! the CPU with `yield_now`, or block with `park`, `WaitQueue`, `sleep` and
+//! `block_on`.
+//!
+//! Whatever a CPU was running before it first switched becomes a thread too,
+//! so the async executor keeps running as one of them. A CPU with nothing to
+//! run switches to its idle thread.
+//!
+//! Threads that keep per-CPU state, like executors, can `pin_current` to stay
+//! on their CPU. They wait in a run queue of that CPU, which takes turns with
+//! the global one in the order threads became ready.
+//!
+//! A thread preempted while holding a spinlock makes others spin on it until
+//! it runs again. Locks that are also taken with interrupts disabled must
+//! therefore always be taken with interrupts disabled, like for interrupt
+//! handlers.
+mod wait_queue;
+
+pub use wait_queue::WaitQueue;
+
+use crate::{
+    arch::{
+        context::{self, Context},
+        percpu::cpu_id,
+        woint,
+    },
+    percpu::per_cpu,
+    time::{clocksource, Duration},
+};
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    task::Wake,
+    vec,
+    vec::Vec,
+};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context as TaskContext, Poll, Waker},
+};
+use spin::{Lazy, Mutex, Once};
+
+/// Size of the stack of spawned threads.
+pub const STACK_SIZE: usize = 4096 * 8;
+/// How long a thread runs before the next ready one gets a turn.
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+/// `Thread::pinned` of threads that can run on any CPU.
+const NOT_PINNED: usize = usize::MAX;
+
+/// Unique ID of a thread.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Scheduling state of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    /// Running on a CPU.
+    Running,
+    /// Waiting in the run queue.
+    Ready,
+    /// Parked until `unpark` is called.
+    Blocked,
+    /// Returned from its entry point.
+    Exited,
+}
+
+impl State {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => State::Running,
+            1 => State::Ready,
+            2 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+}
+
+/// A kernel thread.
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    /// Set by `unpark`, consumed by `park`.
+    token: AtomicBool,
+    /// Whether a CPU is still using the stack of the thread.
+    on_cpu: AtomicBool,
+    /// CPU the thread is pinned to, or `NOT_PINNED`.
+    pinned: AtomicUsize,
+    /// When the thread was last queued, orders the run queues.
+    ready_since: AtomicU64,
+    context: UnsafeCell<Context>,
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    exited: WaitQueue,
+    stack: Option<Box<[u8]>>,
+}
+
+// The context is only accessed by the CPU switching to or away from the
+// thread, `on_cpu` keeps others off
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(
+        name: String,
+        context: Context,
+        stack: Option<Box<[u8]>>,
+        entry: Option<Box<dyn FnOnce() + Send>>,
+    ) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name,
+            state: AtomicU8::new(State::Running as u8),
+            token: AtomicBool::new(false),
+            on_cpu: AtomicBool::new(false),
+            pinned: AtomicUsize::new(NOT_PINNED),
+            ready_since: AtomicU64::new(0),
+            context: UnsafeCell::new(context),
+            entry: Mutex::new(entry),
+            exited: WaitQueue::new(),
+            stack,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the addresses of the stack of a spawned thread.
+    pub fn stack(&self) -> Option<Range<usize>> {
+        let stack = self.stack.as_ref()?;
+        let start = stack.as_ptr() as usize;
+        Some(start..start + stack.len())
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    /// Returns the CPU the thread is pinned to.
+    pub fn pinned_cpu(&self) -> Option<usize> {
+        match self.pinned.load(Ordering::Relaxed) {
+            NOT_PINNED => None,
+            cpu => Some(cpu),
+        }
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    /// Changes the state from `from` to `to`, returns `false` if it wasn't
+    /// `from`.
+    fn transition(&self, from: State, to: State) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Makes the thread runnable if it is parked, or makes its next `park`
+    /// return immediately otherwise. Can be called from interrupt handlers.
+    pub fn unpark(self: &Arc<Self>) {
+        self.token.store(true, Ordering::SeqCst);
+        if self.transition(State::Blocked, State::Ready) {
+            make_ready(self.clone());
+        }
+    }
+
+    /// Blocks until the thread has exited.
+    pub fn join(&self) {
+        self.exited.wait_while(|| self.state() != State::Exited);
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+/// Threads waiting for a CPU. Only locked with interrupts disabled.
+static READY: Lazy<Mutex<VecDeque<Arc<Thread>>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+/// Orders threads queued in `READY` and `PINNED`.
+static READY_COUNT: AtomicU64 = AtomicU64::new(0);
+
+per_cpu! {
+    /// Thread running on the CPU, created on first use.
+    static CURRENT: Mutex<Option<Arc<Thread>>> = Mutex::new(None);
+    /// Threads pinned to the CPU waiting for it, locked before `READY`.
+    /// Only few threads are pinned.
+    static PINNED: Mutex<Vec<Arc<Thread>>> = Mutex::new(Vec::new());
+    /// Thread the CPU just switched away from, see `finish_switch`.
+    static PREVIOUS: Mutex<Option<Arc<Thread>>> = Mutex::new(None);
+    static IDLE: Once<Arc<Thread>> = Once::new();
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+    /// When the running thread got the CPU, in nanoseconds since boot.
+    static SLICE_START: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Returns the running thread.
+pub fn current() -> Arc<Thread> {
+    woint(|| {
+        CURRENT
+            .get()
+            .lock()
+            .get_or_insert_with(|| {
+                let name = match cpu_id() {
+                    0 => "main".to_string(),
+                    id => format!("cpu{}", id),
+                };
+                let thread = Thread::new(name, Context::current(), None, None);
+                thread.on_cpu.store(true, Ordering::Relaxed);
+                Arc::new(thread)
+            })
+            .clone()
+    })
+}
+
+/// Returns the stack of the running thread, if it was spawned. Doesn't wait
+/// for locks, so backtraces can use it while the thread is being switched.
+pub fn current_stack() -> Option<Range<usize>> {
+    woint(|| CURRENT.get().try_lock()?.as_ref()?.stack())
+}
+
+/// Starts a thread running `f`.
+pub fn spawn<F>(name: impl Into<String>, f: F) -> Arc<Thread>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = new_thread(name.into(), Some(Box::new(f)));
+    // Whatever is spawning threads must be a thread to be switched away from
+    current();
+    thread.set_state(State::Ready);
+    make_ready(thread.clone());
+    thread
+}
+
+/// Keeps the running thread on the current CPU from now on.
+pub fn pin_current() {
+    let thread = current();
+    // Can't move between reading the ID and pinning
+    woint(|| thread.pinned.store(cpu_id(), Ordering::Relaxed));
+}
+
+fn new_thread(name: String, entry: Option<Box<dyn FnOnce() + Send>>) -> Arc<Thread> {
+    let stack = vec![0; STACK_SIZE].into_boxed_slice();
+    let stack_top = stack.as_ptr() as usize + STACK_SIZE;
+    let context = unsafe { Context::new(stack_top, thread_start) };
+    Arc::new(Thread::new(name, context, Some(stack), entry))
+}
+
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    context::enable_interrupts();
+
+    let entry = current().entry.lock().take();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Ends the running thread.
+pub fn exit() -> ! {
+    woint(|| {
+        let thread = current();
+        thread.set_state(State::Exited);
+        thread.exited.notify_all();
+        // This stack frame is never returned to
+        drop(thread);
+        schedule();
+    });
+    unreachable!("exited thread was switched back to")
+}
+
+/// Lets the next ready thread run, if there is one.
+pub fn yield_now() {
+    current();
+    woint(schedule);
+}
+
+/// Blocks until the thread is unparked. May return spuriously, so callers
+/// should check what they are waiting for in a loop.
+pub fn park() {
+    let thread = current();
+    woint(|| {
+        thread.set_state(State::Blocked);
+        // An `unpark` that found the thread still running left a token
+        if thread.token.swap(false, Ordering::SeqCst)
+            && thread.transition(State::Blocked, State::Running)
+        {
+            return;
+        }
+        schedule();
+    });
+}
+
+struct ThreadWaker(Arc<Thread>);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Blocks the thread until `future` completes.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(current())));
+    let mut context = TaskContext::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        park();
+    }
+}
+
+/// Blocks the thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    block_on(crate::task::sleep(duration));
+}
+
+/// Whether there are threads waiting for the current CPU.
+pub fn has_ready() -> bool {
+    woint(|| !PINNED.get().lock().is_empty() || !READY.lock().is_empty())
+}
+
+/// Queues `thread` on the run queue of its CPU if it is pinned, or on the
+/// global one.
+fn make_ready(thread: Arc<Thread>) {
+    woint(|| {
+        let since = READY_COUNT.fetch_add(1, Ordering::Relaxed);
+        thread.ready_since.store(since, Ordering::Relaxed);
+        match thread.pinned_cpu() {
+            Some(cpu) => {
+                if let Some(pinned) = PINNED.for_cpu(cpu) {
+                    pinned.lock().push(thread);
+                }
+                // A halted CPU only notices when interrupted
+                #[cfg(target_arch = "x86_64")]
+                crate::arch::x86_64::smp::wake(cpu);
+            }
+            None => READY.lock().push_back(thread),
+        }
+    });
+}
+
+/// Takes the thread that has been ready the longest from the run queues of
+/// the current CPU. Must be called with interrupts disabled.
+fn pop_ready() -> Option<Arc<Thread>> {
+    let mut pinned = PINNED.get().lock();
+    let mut ready = READY.lock();
+    let since = |thread: &Arc<Thread>| thread.ready_since.load(Ordering::Relaxed);
+    match (pinned.first(), ready.front()) {
+        (Some(first), Some(front)) if since(front) < since(first) => ready.pop_front(),
+        (Some(_), _) => Some(pinned.remove(0)),
+        (None, _) => ready.pop_front(),
+    }
+}
+
+/// Ends the time slice of the running thread if it is used up and others are
+/// ready. Called from the timer interrupt.
+pub fn tick() {
+    let elapsed = clocksource::now_ns().saturating_sub(SLICE_START.get().load(Ordering::Relaxed));
+    if Duration::from_nanos(elapsed) >= TIME_SLICE && has_ready() {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches threads if the time slice of the running one ended. Called by
+/// architecture code before returning from an interrupt to non-interrupt
+/// code, with interrupts disabled.
+pub fn preempt() {
+    if NEED_RESCHED.get().swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+fn idle_thread() -> Arc<Thread> {
+    IDLE.get()
+        .call_once(|| new_thread("idle".to_string(), Some(Box::new(idle))))
+        .clone()
+}
+
+fn idle() {
+    loop {
+        context::wait_for_work(has_ready);
+        yield_now();
+    }
+}
+
+/// Switches to the next ready thread. The running thread is put back in the
+/// run queue unless it blocked or exited. Must be called with interrupts
+/// disabled.
+fn schedule() {
+    let prev = match CURRENT.get().lock().clone() {
+        Some(prev) => prev,
+        // Not a thread yet, nothing to switch away from
+        None => return,
+    };
+    let next = match pop_ready() {
+        Some(next) => next,
+        None if prev.state() == State::Running => return,
+        None => idle_thread(),
+    };
+    if Arc::ptr_eq(&prev, &next) {
+        // Unparked before it could switch away
+        prev.set_state(State::Running);
+        return;
+    }
+
+    let is_idle = IDLE
+        .get()
+        .get()
+        .map_or(false, |idle| Arc::ptr_eq(idle, &prev));
+    if prev.transition(State::Running, State::Ready) && !is_idle {
+        make_ready(prev.clone());
+    }
+
+    // The CPU that ran `next` last might not have switched away from it yet
+    while next.on_cpu.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    next.on_cpu.store(true, Ordering::Relaxed);
+    next.set_state(State::Running);
+    SLICE_START
+        .get()
+        .store(clocksource::now_ns(), Ordering::Relaxed);
+
+    let from = prev.context.get();
+    let to = next.context.get();
+    *CURRENT.get().lock() = Some(next);
+    *PREVIOUS.get().lock() = Some(prev);
+    unsafe { context::switch(from, to) };
+    finish_switch();
+}
+
+/// Lets other CPUs run the thread this one switched away from, now that it
+/// is on another stack.
+fn finish_switch() {
+    let prev = PREVIOUS.get().lock().take();
+    if let Some(prev) = prev {
+        prev.on_cpu.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_spawn_join() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    serial_print!("test_spawn_join... ");
+    let thread = spawn("test", || RAN.store(true, Ordering::Relaxed));
+    thread.join();
+    assert!(RAN.load(Ordering::Relaxed));
+    assert_eq!(thread.state(), State::Exited);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,65 @@
+//! Queues of threads waiting for a condition.
+use super::{current, park, Thread};
+use crate::arch::woint;
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+/// Threads blocked until another thread or an interrupt handler notifies
+/// them.
+pub struct WaitQueue {
+    waiters: Mutex<Vec<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Blocks the running thread while `condition` returns `true`. Whatever
+    /// makes it return `false` has to notify the queue afterwards.
+    pub fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        let thread = current();
+        loop {
+            // Queue up before checking, so a notification inbetween isn't lost
+            woint(|| {
+                let mut waiters = self.waiters.lock();
+                if !waiters.iter().any(|waiter| Arc::ptr_eq(waiter, &thread)) {
+                    waiters.push(thread.clone());
+                }
+            });
+            if !condition() {
+                woint(|| {
+                    self.waiters
+                        .lock()
+                        .retain(|waiter| !Arc::ptr_eq(waiter, &thread));
+                });
+                return;
+            }
+            park();
+        }
+    }
+
+    /// Wakes the thread that has been waiting the longest. Returns `false`
+    /// if there was none.
+    pub fn notify_one(&self) -> bool {
+        let waiter = woint(|| {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        });
+        waiter.map(|waiter| waiter.unpark()).is_some()
+    }
+
+    /// Wakes all waiting threads.
+    pub fn notify_all(&self) {
+        let waiters = woint(|| core::mem::take(&mut *self.waiters.lock()));
+        for waiter in waiters {
+            waiter.unpark();
+        }
+    }
+}
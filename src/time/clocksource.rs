@@ -0,0 +1,84 @@
+//! Clock sources, the hardware counters `Instant`s are read from.
+//!
+//! Architecture code passes every clock source it found to `select` at boot,
+//! which picks the one with the highest rating. Until then, and if nothing
+//! better is found, the tick count is used.
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+
+/// A free running, monotonic hardware counter.
+pub trait ClockSource: Sync {
+    /// Name shown in logs.
+    fn name(&self) -> &'static str;
+
+    /// How good this clock source is, the highest rated one gets picked.
+    /// Sub-microsecond resolution sources should be rated above 100.
+    fn rating(&self) -> u32;
+
+    /// Returns the nanoseconds passed since an arbitrary point in time.
+    fn now_ns(&self) -> u64;
+}
+
+/// Clock source backed by the tick count. Low resolution, but always
+/// available.
+pub struct Ticks;
+
+impl ClockSource for Ticks {
+    fn name(&self) -> &'static str {
+        "ticks"
+    }
+
+    fn rating(&self) -> u32 {
+        1
+    }
+
+    fn now_ns(&self) -> u64 {
+        super::tick_nanos()
+    }
+}
+
+static CURRENT: Once<&'static dyn ClockSource> = Once::new();
+/// Added to the readings of `CURRENT` so that time continues from where the
+/// tick count was when it was selected.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Picks the best of `candidates` (and `Ticks`) as the clock source. Only
+/// the first call has an effect.
+pub fn select(candidates: &[&'static dyn ClockSource]) {
+    let best = candidates
+        .iter()
+        .copied()
+        .fold(&Ticks as &'static dyn ClockSource, |best, c| {
+            if c.rating() > best.rating() {
+                c
+            } else {
+                best
+            }
+        });
+
+    CURRENT.call_once(|| {
+        let offset = super::tick_nanos().wrapping_sub(best.now_ns());
+        OFFSET.store(offset, Ordering::Relaxed);
+        best
+    });
+    log::info!("using {} as clock source", current().name());
+}
+
+/// Returns the clock source in use.
+pub fn current() -> &'static dyn ClockSource {
+    CURRENT.get().copied().unwrap_or(&Ticks)
+}
+
+/// Whether time keeping depends on the periodic tick. If not, the tick
+/// source may be stopped while idle.
+pub fn needs_tick() -> bool {
+    current().rating() <= Ticks.rating()
+}
+
+/// Returns the nanoseconds since boot, read from the current clock source.
+pub fn now_ns() -> u64 {
+    match CURRENT.get() {
+        Some(source) => source.now_ns().wrapping_add(OFFSET.load(Ordering::Relaxed)),
+        None => super::tick_nanos(),
+    }
+}
@@ -0,0 +1,226 @@
+//! Monotonic time keeping.
+//!
+//! A tick source (the PIT on `x86_64`, the boot core's generic timer on
+//! `aarch64`) calls `tick` from its interrupt handler at `tick_rate` Hz.
+//! `Instant`s are read from the best clock source picked at boot, falling
+//! back to the tick count. The date is kept in `wall`.
+pub mod clocksource;
+pub mod wall;
+
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+
+pub use core::time::Duration;
+
+/// Tick rate tick sources are programmed with by default.
+pub const DEFAULT_TICK_RATE: u64 = 1000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_RATE: AtomicU64 = AtomicU64::new(0);
+/// Point of the last rate change, only ticks after it use the current rate.
+static EPOCH: Mutex<TickEpoch> = Mutex::new(TickEpoch {
+    ticks: 0,
+    nanos: 0,
+    rate: 0,
+});
+
+struct TickEpoch {
+    /// Tick count at the rate change.
+    ticks: u64,
+    /// Nanoseconds since boot at the rate change.
+    nanos: u64,
+    rate: u64,
+}
+
+impl TickEpoch {
+    /// Returns the nanoseconds since boot at tick count `ticks`.
+    #[allow(clippy::cast_possible_truncation)]
+    fn nanos_at(&self, ticks: u64) -> u64 {
+        if self.rate == 0 {
+            return self.nanos;
+        }
+        let elapsed = u128::from(ticks.saturating_sub(self.ticks)) * u128::from(NANOS_PER_SEC)
+            / u128::from(self.rate);
+        self.nanos.saturating_add(elapsed as u64)
+    }
+}
+
+/// Advances the tick count by one. Called by the tick source's interrupt
+/// handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of ticks since boot. Ticks are only regular while
+/// the tick source isn't stopped for tickless idle.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Sets the rate at which `tick` is called, in Hz. Called by the tick source
+/// when it is (re)programmed. Ticks before the change keep their old length.
+pub fn set_tick_rate(hz: u64) {
+    crate::arch::woint(|| {
+        let mut epoch = EPOCH.lock();
+        let now = ticks();
+        epoch.nanos = epoch.nanos_at(now);
+        epoch.ticks = now;
+        epoch.rate = hz;
+        TICK_RATE.store(hz, Ordering::Relaxed);
+    });
+}
+
+/// Returns the rate at which `tick` is called, in Hz. Zero if there is no
+/// tick source.
+pub fn tick_rate() -> u64 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+/// Returns the nanoseconds since boot, derived from the tick count.
+fn tick_nanos() -> u64 {
+    crate::arch::woint(|| EPOCH.lock().nanos_at(ticks()))
+}
+
+/// Returns the time elapsed since boot.
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant::BOOT)
+}
+
+/// A point in time, measured from boot. Never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// The instant the system booted.
+    pub const BOOT: Instant = Instant { nanos: 0 };
+
+    /// Returns the current instant.
+    pub fn now() -> Self {
+        Instant {
+            nanos: clocksource::now_ns(),
+        }
+    }
+
+    /// Creates an instant from nanoseconds since boot.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant { nanos }
+    }
+
+    /// Returns the nanoseconds since boot.
+    pub const fn as_nanos(self) -> u64 {
+        self.nanos
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if
+    /// `earlier` is later than `self`.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// Returns the time elapsed since `self`.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(Instant::from_nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Display for Instant {
+    /// Formats as seconds since boot, with microsecond precision.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06}",
+            self.nanos / NANOS_PER_SEC,
+            self.nanos % NANOS_PER_SEC / 1000
+        )
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_instant_arithmetic() {
+    serial_print!("test_instant_arithmetic... ");
+    let start = Instant::from_nanos(1_500);
+    let later = start + Duration::from_micros(2);
+    assert_eq!(later.as_nanos(), 3_500);
+    assert_eq!(later - start, Duration::from_micros(2));
+    assert_eq!(start - later, Duration::from_secs(0));
+    assert_eq!(Instant::BOOT.checked_sub(Duration::from_nanos(1)), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_tick_epoch_rate_change() {
+    serial_print!("test_tick_epoch_rate_change... ");
+    let mut epoch = TickEpoch {
+        ticks: 0,
+        nanos: 0,
+        rate: 1000,
+    };
+    assert_eq!(epoch.nanos_at(500), 500_000_000);
+    // Ticks before the change keep their length
+    epoch.nanos = epoch.nanos_at(500);
+    epoch.ticks = 500;
+    epoch.rate = 100;
+    assert_eq!(epoch.nanos_at(500), 500_000_000);
+    assert_eq!(epoch.nanos_at(510), 600_000_000);
+    serial_println!("[ok]");
+}
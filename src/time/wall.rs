@@ -0,0 +1,267 @@
+//! Wall clock time.
+//!
+//! The date is read once at boot from a real-time clock, after that the
+//! wall clock advances with the monotonic clock.
+use super::{Duration, Instant, NANOS_PER_SEC};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const SECS_PER_DAY: u64 = 86_400;
+/// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar.
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+/// Days in a 400 year cycle.
+const DAYS_PER_ERA: u64 = 146_097;
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// Error returned for dates that can't be converted to Unix time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateError {
+    /// A field is out of range, like month 13 or February 30th.
+    Invalid,
+    /// The date is before the Unix epoch.
+    BeforeEpoch,
+    /// Nanoseconds since the Unix epoch don't fit in 64 bits, past 2554.
+    TooLate,
+}
+
+/// A UTC calendar date and time. Dates before the Unix epoch can't be
+/// converted to Unix time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// Returns the date and time `nanos` nanoseconds after the Unix epoch.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn from_unix_nanos(nanos: u64) -> Self {
+        let secs = nanos / NANOS_PER_SEC;
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let secs_of_day = secs % SECS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            nanosecond: (nanos % NANOS_PER_SEC) as u32,
+        }
+    }
+
+    /// Checks that all fields are in range.
+    ///
+    /// # Errors
+    /// Returns `DateError::Invalid` if a field is out of range.
+    pub fn validate(&self) -> Result<(), DateError> {
+        let valid = (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && u64::from(self.nanosecond) < NANOS_PER_SEC;
+        if valid {
+            Ok(())
+        } else {
+            Err(DateError::Invalid)
+        }
+    }
+
+    /// Returns the nanoseconds since the Unix epoch.
+    ///
+    /// # Errors
+    /// Returns an error if the date is invalid or before the epoch.
+    pub fn to_unix_nanos(&self) -> Result<u64, DateError> {
+        let days = self.unix_days()?;
+        let secs = days * SECS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        secs.checked_mul(NANOS_PER_SEC)
+            .and_then(|nanos| nanos.checked_add(u64::from(self.nanosecond)))
+            .ok_or(DateError::TooLate)
+    }
+
+    /// Returns the day of the week, 0 being Sunday.
+    ///
+    /// # Errors
+    /// Returns an error if the date is invalid or before the epoch.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn weekday(&self) -> Result<u8, DateError> {
+        // 1970-01-01 was a Thursday
+        Ok(((self.unix_days()? + 4) % 7) as u8)
+    }
+
+    /// Returns the name of the day of the week.
+    ///
+    /// # Errors
+    /// Returns an error if the date is invalid or before the epoch.
+    pub fn weekday_name(&self) -> Result<&'static str, DateError> {
+        Ok(WEEKDAYS[usize::from(self.weekday()?)])
+    }
+
+    fn unix_days(&self) -> Result<u64, DateError> {
+        self.validate()?;
+        days_from_civil(self.year, self.month, self.day)
+            .checked_sub(UNIX_EPOCH_DAYS)
+            .ok_or(DateError::BeforeEpoch)
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats as ISO 8601 with microseconds, e.g. `2021-03-14 15:09:26.535897`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.nanosecond / 1000
+        )
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Returns the days since 0000-03-01 of a valid date, so January and
+/// February of year 0 can't be represented and count as day 0.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    // Years start in March, so the leap day is at the end
+    let year = match u64::from(year).checked_sub(u64::from(month <= 2)) {
+        Some(year) => year,
+        None => return 0,
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month = u64::from(month);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era
+}
+
+/// Returns the year, month and day of a day since the Unix epoch.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+#[allow(clippy::cast_possible_truncation)]
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + UNIX_EPOCH_DAYS;
+    let era = days / DAYS_PER_ERA;
+    let day_of_era = days % DAYS_PER_ERA;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year as u16, month as u8, day as u8)
+}
+
+/// Unix time in nanoseconds at `Instant::BOOT`, zero if unknown.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the wall clock to `now`. Called by the real-time clock driver.
+///
+/// # Errors
+/// Returns an error, leaving the wall clock unset, if `now` is invalid or
+/// before the Unix epoch.
+pub fn set(now: DateTime) -> Result<(), DateError> {
+    let boot = now
+        .to_unix_nanos()?
+        .saturating_sub(Instant::now().as_nanos());
+    BOOT_TIME.store(boot.max(1), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the current date and time, if the wall clock has been set.
+pub fn now() -> Option<DateTime> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(DateTime::from_unix_nanos(boot + Instant::now().as_nanos())),
+    }
+}
+
+/// Returns the time since the Unix epoch, if the wall clock has been set.
+pub fn unix_time() -> Option<Duration> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(Duration::from_nanos(boot + Instant::now().as_nanos())),
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_calendar_conversion() {
+    serial_print!("test_calendar_conversion... ");
+    let epoch = DateTime::from_unix_nanos(0);
+    assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+    assert_eq!(epoch.weekday_name(), Ok("Thursday"));
+
+    // 2000-02-29 12:34:56.789
+    let nanos = 951_827_696_789_000_000;
+    let date = DateTime::from_unix_nanos(nanos);
+    assert_eq!((date.year, date.month, date.day), (2000, 2, 29));
+    assert_eq!((date.hour, date.minute, date.second), (12, 34, 56));
+    assert_eq!(date.to_unix_nanos(), Ok(nanos));
+    assert_eq!(date.weekday_name(), Ok("Tuesday"));
+
+    // What an RTC with garbage or a missing century could report
+    let before_epoch = DateTime { year: 1969, ..date };
+    assert_eq!(before_epoch.to_unix_nanos(), Err(DateError::BeforeEpoch));
+    let year_zero = DateTime {
+        year: 0,
+        month: 1,
+        ..date
+    };
+    assert_eq!(year_zero.to_unix_nanos(), Err(DateError::BeforeEpoch));
+    let far = DateTime { year: 9996, ..date };
+    assert_eq!(far.to_unix_nanos(), Err(DateError::TooLate));
+    let invalid = DateTime { month: 13, ..date };
+    assert_eq!(invalid.to_unix_nanos(), Err(DateError::Invalid));
+    assert!(DateTime { year: 2001, ..date }.validate().is_err());
+    serial_println!("[ok]");
+}